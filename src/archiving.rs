@@ -1,11 +1,13 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 use zstd::bulk::{compress, decompress};
 use base64::prelude::*;
 
-use crate::{println, ChildCycles, Clipboard, ClipboardPlugin, Cycle, CycleWaveBundle, Hover, MousePos, Segment, Wave};
+use crate::{println, BandLimited, ChildCycles, Clipboard, ClipboardPlugin, Cycle, CycleWaveBundle, Hover, ActiveTuning, Interpolation, KeyboardMap, Modulation, MousePos, Relative, Scale, Scene, SceneNode, Segment, Tuning, Wave};
 
 pub struct ArchivingPlugin;
 
@@ -31,14 +33,166 @@ struct Node {
     phase: f32,
     position: Vec2,
     color: LinearRgba,
+    options: Vec<NodeOption>,
 }
 
+/**
+ * Node settings that were added after the first release.
+ * New variants must be appended, such that existing share strings keep decoding.
+ */
 #[derive(Serialize, Deserialize, Debug)]
-struct Tree{
+enum NodeOption {
+    Interpolation(Interpolation),
+    Pan(f32),
+    AutoPan(bool),
+    Gain(f32),
+    LegacyDc(bool),
+    Modulation(Modulation),
+    Depth(f32),
+    Offset(f32),
+    Cents(f32),
+    Ratio(u32, u32),
+    Hz(f64),
+    Tuning(Scale, KeyboardMap),
+    Relative(Relative),
+    /** Plays the default frequency list while the parent has a tuning. */
+    DefaultTuning,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tree{
     nodes: Vec<Node>,
     waves: Vec<WavePattern>,
 }
 
+/** Share strings created before nodes had options. */
+mod legacy {
+    use bevy::prelude::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub(super) struct Node {
+        pub(super) parent: u32,
+        pub(super) frequency: u32,
+        pub(super) wave: u32,
+        pub(super) phase: f32,
+        pub(super) position: Vec2,
+        pub(super) color: LinearRgba,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct Tree {
+        pub(super) nodes: Vec<Node>,
+        pub(super) waves: Vec<super::WavePattern>,
+    }
+}
+
+impl From<legacy::Tree> for Tree {
+    fn from(tree: legacy::Tree) -> Self {
+        Tree{
+            nodes: tree.nodes.into_iter().map(|node| Node{
+                parent: node.parent,
+                frequency: node.frequency,
+                wave: node.wave,
+                phase: node.phase,
+                position: node.position,
+                color: node.color,
+                // Keep these songs sounding the way they were made.
+                options: vec![NodeOption::LegacyDc(true)],
+            }).collect(),
+            waves: tree.waves,
+        }
+    }
+}
+
+impl Tree {
+    fn pattern(&self, node: &Node) -> [f32; Wave::LENGTH] {
+        let wave = &self.waves[node.wave as usize];
+        let mut pattern = [0.0; Wave::LENGTH];
+        for i in 0..Wave::LENGTH {
+            pattern[i] = wave.0[i] as f32 / 65535.0;
+        }
+        pattern
+    }
+
+    /** The cycles of all nodes, which share the tuning of their parent unless they have their own. */
+    fn cycles(&self) -> Vec<Cycle> {
+        let mut cycles: Vec<Cycle> = Vec::with_capacity(self.nodes.len());
+        for (index, node) in self.nodes.iter().enumerate() {
+            let parent = if index == 0 {None} else {cycles.get(node.parent as usize)};
+            let cycle = self.cycle(node, parent.and_then(|parent| parent.tuning.clone()));
+            cycles.push(cycle);
+        }
+        cycles
+    }
+
+    fn cycle(&self, node: &Node, tuning: Option<Arc<Tuning>>) -> Cycle {
+        let mut cycle = Cycle{
+            frequency: node.frequency,
+            phase: node.phase,
+            color: node.color,
+            tuning,
+            ..default()
+        };
+        for option in node.options.iter() {
+            match *option {
+                NodeOption::Interpolation(interpolation) => cycle.interpolation = interpolation,
+                NodeOption::Pan(pan) => cycle.pan = pan,
+                NodeOption::AutoPan(auto_pan) => cycle.auto_pan = auto_pan,
+                NodeOption::Gain(gain_db) => cycle.gain_db = gain_db,
+                NodeOption::LegacyDc(legacy_dc) => cycle.legacy_dc = legacy_dc,
+                NodeOption::Modulation(modulation) => cycle.modulation = modulation,
+                NodeOption::Depth(depth) => cycle.depth = depth,
+                NodeOption::Offset(offset) => cycle.offset = offset,
+                NodeOption::Cents(cents) => cycle.cents = cents,
+                NodeOption::Ratio(p, q) => cycle.ratio = (p, q),
+                NodeOption::Hz(hz) => cycle.hz = Some(hz),
+                // Share strings from before the tuning was stored once per tree repeat it on every node.
+                NodeOption::Tuning(ref scale, ref map) if cycle.tuning.as_ref().is_some_and(|tuning| tuning.scale == *scale && tuning.map == *map) => (),
+                NodeOption::Tuning(ref scale, ref map) => match Tuning::new(scale.clone(), map.clone()) {
+                    Ok(tuning) => cycle.tuning = Some(Arc::new(tuning)),
+                    Err(err) => println!("Failed to load tuning: {}", err),
+                },
+                NodeOption::Relative(relative) => cycle.relative = relative,
+                NodeOption::DefaultTuning => cycle.tuning = None,
+            }
+        }
+        cycle
+    }
+
+    /** Adds the tree to the scene as a new root in which every node is playing. */
+    pub fn add_to_scene(&self, scene: &mut Scene) {
+        let offset = scene.nodes.len();
+        let mut leaf = vec![true; self.nodes.len()];
+        for node in self.nodes.iter().skip(1) {
+            leaf[node.parent as usize] = false;
+        }
+
+        for ((index, node), cycle) in self.nodes.iter().enumerate().zip(self.cycles()) {
+            let pattern = self.pattern(node);
+            let parent = if index == 0 {None} else {Some(offset + node.parent as usize)};
+            scene.push(parent, SceneNode::new(&cycle, pattern, Arc::new(BandLimited::new(&pattern)), leaf[index]));
+        }
+    }
+}
+
+/** Decodes a tree from the text created by copy_tree. */
+pub fn decode_tree(text: &str) -> Result<Tree, String> {
+    let compressed = BASE64_URL_SAFE_NO_PAD.decode(text).map_err(|err| format!("{:?}", err))?;
+    let serialized = decompress(&compressed, 64 * 1024 * 1024).map_err(|err| format!("{:?}", err))?; // Max uncompressed filesize is 64 MB.
+    match bitcode::deserialize::<Tree>(&serialized) {
+        Ok(tree) => Ok(tree),
+        Err(err) => bitcode::deserialize::<legacy::Tree>(&serialized).map(Tree::from).map_err(|_| format!("{:?}", err)),
+    }
+}
+
+/** Encodes a tree as the text that decode_tree reads. */
+fn encode_tree(tree: &Tree) -> Result<String, String> {
+    let serialized = bitcode::serialize(tree).map_err(|err| format!("{:?}", err))?;
+    let compressed = compress(&serialized, 0).map_err(|err| format!("{:?}", err))?;
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(&compressed))
+}
+
 pub fn copy_tree(
     q_cycles: Query<(&Cycle, &Wave, &Transform)>,
     q_children: Query<&ChildCycles>,
@@ -55,6 +209,7 @@ pub fn copy_tree(
 
     let mut stack: Vec<(u32,Entity)> = Vec::new();
     stack.push((0, root));
+    let mut tunings: Vec<Option<Arc<Tuning>>> = Vec::new();
 
     while let Some((parent, node)) = stack.pop() {
         let Ok((cycle, wave, transform)) = q_cycles.get(node) else {continue};
@@ -72,6 +227,29 @@ pub fn copy_tree(
 
         // Insert node into table
         let node_id = tree.nodes.len() as u32;
+        let mut options = vec![
+            NodeOption::Interpolation(cycle.interpolation),
+            NodeOption::Pan(cycle.pan),
+            NodeOption::AutoPan(cycle.auto_pan),
+            NodeOption::Gain(cycle.gain_db),
+            NodeOption::LegacyDc(cycle.legacy_dc),
+            NodeOption::Modulation(cycle.modulation),
+            NodeOption::Depth(cycle.depth),
+            NodeOption::Offset(cycle.offset),
+            NodeOption::Cents(cycle.cents),
+            NodeOption::Ratio(cycle.ratio.0, cycle.ratio.1),
+            NodeOption::Relative(cycle.relative),
+        ];
+        options.extend(cycle.hz.map(NodeOption::Hz));
+        // The tuning is only stored where it differs from the parent, usually just on the root.
+        let inherited = if node_id == 0 {None} else {tunings[parent as usize].as_ref()};
+        match (&cycle.tuning, inherited) {
+            (Some(tuning), Some(inherited)) if tuning.is_same(inherited) => (),
+            (Some(tuning), _) => options.push(NodeOption::Tuning(tuning.scale.clone(), tuning.map.clone())),
+            (None, Some(_)) => options.push(NodeOption::DefaultTuning),
+            (None, None) => (),
+        }
+        tunings.push(cycle.tuning.clone());
         tree.nodes.push(Node{
             parent,
             frequency: cycle.frequency,
@@ -79,6 +257,7 @@ pub fn copy_tree(
             phase: cycle.phase,
             position: transform.translation.truncate(),
             color: cycle.color,
+            options,
         });
         
         // Iterate over children
@@ -89,54 +268,47 @@ pub fn copy_tree(
         }
     }
 
-    let serialized = match bitcode::serialize(&tree) {
+    match encode_tree(&tree) {
         Ok(ok) => ok,
-        Err(err) => { println!("Failed to copy tree: {:?}", err); return default() }
-    };
-    let compressed = match compress(&serialized, 0) {
-        Ok(ok) => ok,
-        Err(err) => { println!("Failed to copy tree: {:?}", err); return default() }
-    };
-    return BASE64_URL_SAFE_NO_PAD.encode(&compressed).into();
+        Err(err) => { println!("Failed to copy tree: {}", err); default() }
+    }
 }
 
 pub fn paste_tree(
     text: In<String>,
     mut commands: Commands,
     mouse: Res<MousePos>,
+    hover: Res<Hover>,
+    mut retuning: Retuning,
 ) {
-    let compressed = match BASE64_URL_SAFE_NO_PAD.decode(text.0) {
-        Ok(ok) => ok,
-        Err(err) => { println!("Failed to paste tree: {:?}", err); return }
-    };
-    let serialized = match decompress(&compressed, 64 * 1024 * 1024) { // Max uncompressed filesize is 64 MB.
-        Ok(ok) => ok,
-        Err(err) => { println!("Failed to paste tree: {:?}", err); return }
-    };
-    let tree = match bitcode::deserialize::<Tree>(&serialized) {
+    let tree = match decode_tree(&text.0) {
         Ok(ok) => ok,
-        Err(err) => { println!("Failed to paste tree: {:?}", err); return }
+        Err(err) => {
+            // Pasting a .scl or .kbm file retunes the hovered tree and new circles.
+            match Tuning::with_pasted(retuning.active_tuning.0.as_deref(), &text.0) {
+                Some(Ok(tuning)) => retuning.retune(Arc::new(tuning), hover.entity),
+                Some(Err(err)) => println!("Failed to paste tuning: {}", err),
+                None => println!("Failed to paste tree: {}", err),
+            }
+            return
+        }
     };
+    spawn_tree(&mut commands, mouse.position, &tree);
+}
 
+/** Spawns the circles of the tree, with the root at the given position. */
+pub fn spawn_tree(commands: &mut Commands, position: Vec2, tree: &Tree) {
     let mut entities = Vec::<Entity>::new();
-    for node in tree.nodes.iter() {
+    for (node, cycle) in tree.nodes.iter().zip(tree.cycles()) {
         let root = entities.is_empty();
-        let wave = &tree.waves[node.wave as usize];
-        let mut pattern = [0.0; Wave::LENGTH];
-        for i in 0..Wave::LENGTH {
-            pattern[i] = wave.0[i] as f32 / 65535.0;
-        }
+        let pattern = tree.pattern(node);
         let mut ec = commands.spawn(CycleWaveBundle{
-            cycle: Cycle{
-                frequency: node.frequency,
-                phase: node.phase,
-                color: node.color,
-            },
+            cycle,
             wave: Wave{
                 pattern,
                 ..default()
             },
-            transform: Transform::from_translation(if root {mouse.position} else {node.position}.extend(0.0)),
+            transform: Transform::from_translation(if root {position} else {node.position}.extend(0.0)),
             ..default()
         });
         let id = ec.id();
@@ -144,7 +316,98 @@ pub fn paste_tree(
         if !root {
             let parent = entities[node.parent as usize];
             ec.set_parent(parent);
-            Segment::spawn(&mut commands, id, Some(parent));
+            Segment::spawn(commands, id, Some(parent));
+        }
+    }
+}
+
+/** What paste_tree needs to retune a tree and the new circles. */
+#[derive(SystemParam)]
+pub struct Retuning<'w, 's> {
+    active_tuning: ResMut<'w, ActiveTuning>,
+    q_cycles: Query<'w, 's, &'static mut Cycle>,
+    q_children: Query<'w, 's, &'static ChildCycles>,
+    q_parents: Query<'w, 's, &'static Parent, With<Cycle>>,
+}
+
+impl Retuning<'_, '_> {
+    /** Gives the tuning to the whole tree that contains the given circle, and to new circles. */
+    fn retune(&mut self, tuning: Arc<Tuning>, entity: Option<Entity>) {
+        println!("Tuning: {}", tuning.scale.description);
+        let root = entity.map(|entity| self.q_parents.iter_ancestors(entity).last().unwrap_or(entity));
+        let mut stack: Vec<Entity> = root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if let Ok(mut cycle) = self.q_cycles.get_mut(node) {
+                cycle.tuning = Some(tuning.clone());
+            }
+            if let Ok(children) = self.q_children.get(node) {
+                stack.extend(children.0.iter());
+            }
         }
+        self.active_tuning.0 = Some(tuning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::START_TUNE;
+
+    #[test]
+    fn start_tune_decodes_as_legacy_tree() {
+        let compressed = BASE64_URL_SAFE_NO_PAD.decode(START_TUNE).unwrap();
+        let serialized = decompress(&compressed, 64 * 1024 * 1024).unwrap();
+        assert!(bitcode::deserialize::<Tree>(&serialized).is_err());
+
+        let tree = decode_tree(START_TUNE).unwrap();
+        assert_eq!(tree.nodes.len(), 63);
+        for node in tree.nodes.iter() {
+            assert!(matches!(node.options[..], [NodeOption::LegacyDc(true)]));
+            assert!((node.wave as usize) < tree.waves.len());
+        }
+    }
+
+    #[test]
+    fn tree_with_every_option_round_trips() {
+        let scale = Scale::parse("Pentatonic\n5\n200.0\n400.0\n700.0\n900.0\n2/1\n").unwrap();
+        let node = |parent, options| Node{
+            parent,
+            frequency: Cycle::NOTE_A4,
+            wave: 0,
+            phase: 0.25,
+            position: Vec2::new(1.0, -2.0),
+            color: LinearRgba::new(0.1, 0.2, 0.3, 1.0),
+            options,
+        };
+        let tree = Tree{
+            nodes: vec![
+                node(0, vec![
+                    NodeOption::Interpolation(Interpolation::Cubic),
+                    NodeOption::Pan(-0.5),
+                    NodeOption::AutoPan(true),
+                    NodeOption::Gain(-6.0),
+                    NodeOption::LegacyDc(true),
+                    NodeOption::Modulation(Modulation::Phase),
+                    NodeOption::Depth(0.75),
+                    NodeOption::Offset(-0.25),
+                    NodeOption::Cents(12.0),
+                    NodeOption::Ratio(3, 2),
+                    NodeOption::Hz(441.5),
+                    NodeOption::Tuning(scale, KeyboardMap::default()),
+                    NodeOption::Relative(Relative::Root(-3)),
+                ]),
+                node(0, vec![NodeOption::DefaultTuning, NodeOption::Relative(Relative::Parent(7))]),
+            ],
+            waves: vec![WavePattern((0..Wave::LENGTH as u16).collect())],
+        };
+        let decoded = decode_tree(&encode_tree(&tree).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", tree));
+
+        let cycles = decoded.cycles();
+        assert_eq!(cycles[0].ratio, (3, 2));
+        assert_eq!(cycles[0].hz, Some(441.5));
+        assert_eq!(cycles[0].tuning.as_ref().map(|tuning| tuning.scale.degrees.len()), Some(5));
+        assert_eq!(cycles[1].relative, Relative::Parent(7));
+        assert!(cycles[1].tuning.is_none());
     }
 }
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use bevy::prelude::default;
+
+use crate::{println, Arena, BandLimited, Cycle, Interpolation, LoopTunesBackend, Scene, SceneNode, Wave};
+
+/**
+ * Runs the mixer benchmark if it was requested on the command line.
+ * Returns false if the game should be started instead.
+ */
+pub fn run_from_args() -> bool {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) != Some("bench") {return false}
+    benchmark();
+    true
+}
+
+fn node(frequency: f64, phase: f64, generator: fn(f32) -> f32, interpolation: Interpolation, leaf: bool) -> SceneNode {
+    let pattern = Wave::new(generator).pattern;
+    let cycle = Cycle{
+        hz: Some(frequency),
+        phase: phase as f32,
+        interpolation,
+        ..default()
+    };
+    SceneNode::new(&cycle, pattern, Arc::new(BandLimited::new(&pattern)), leaf)
+}
+
+/**
+ * Builds a tree with the given number of leaves: a slow root envelope with 8 rhythm nodes,
+ * whose leaves are spread over 4 phases, such that some of them can share their parent's evaluation.
+ */
+fn benchmark_tree(leaves: usize) -> Scene {
+    benchmark_forest(leaves, 1)
+}
+
+/** Splits the leaves of the benchmark tree over the given number of root trees. */
+fn benchmark_forest(leaves: usize, trees: usize) -> Scene {
+    let mut scene = Scene::default();
+    for tree in 0..trees {
+        let root = scene.push(None, node(0.0078125 * (tree + 1) as f64, 0.0, Wave::SINE, Interpolation::Nearest, false));
+        for group in 0..8 {
+            let rhythm = scene.push(Some(root), node(1.0, group as f64 / 8.0, Wave::SAWTOOTH, Interpolation::Linear, false));
+            for leaf in 0..leaves / trees / 8 {
+                let interpolation = if leaf % 2 == 0 {Interpolation::Nearest} else {Interpolation::Cubic};
+                let frequency = 110.0 * (1.0 + (leaf + tree) as f64 / 16.0);
+                scene.push(Some(rhythm), node(frequency, (leaf % 4) as f64 / 4.0, Wave::SQUARE, interpolation, true));
+            }
+        }
+    }
+    scene
+}
+
+/**
+ * Mixes benchmark trees of increasing size, showing how the mixer scales with the number of leaves.
+ * Then mixes the largest one split over multiple roots, both single-threaded and in parallel,
+ * and checks that both give the exact same samples.
+ */
+fn benchmark() {
+    let sample_rate = LoopTunesBackend::DEFAULT_SAMPLE_RATE;
+    println!("{:>8} {:>8} {:>14} {:>12}", "leaves", "nodes", "ms per second", "us per leaf");
+    for leaves in [16, 64, 256, 1024] {
+        let scene = benchmark_tree(leaves);
+        let mut arena = Arena::default();
+        let (milliseconds, _) = time_mix(sample_rate, |time| scene.mix(time, sample_rate, &mut arena));
+        println!("{:>8} {:>8} {:>14.2} {:>12.2}", leaves, scene.nodes.len(), milliseconds, milliseconds * 1000.0 / leaves as f64);
+    }
+
+    println!();
+    println!("{:>8} {:>8} {:>14} {:>12}", "roots", "leaves", "ms single", "ms parallel");
+    let pool = Scene::mixer_pool();
+    for trees in [2, 4, 8] {
+        let scene = benchmark_forest(1024, trees);
+        let mut arena = Arena::default();
+        let mut arenas = Vec::new();
+        let (single, expected) = time_mix(sample_rate, |time| scene.mix(time, sample_rate, &mut arena));
+        let (parallel, actual) = time_mix(sample_rate, |time| scene.mix_parallel(time, sample_rate, &pool, &mut arenas));
+        println!("{:>8} {:>8} {:>14.2} {:>12.2}", trees, 1024, single, parallel);
+        if expected != actual {
+            println!("Parallel mix differs from single-threaded mix");
+        }
+    }
+}
+
+/** Returns the milliseconds needed to mix one second of audio and a checksum of the mixed samples. */
+fn time_mix(sample_rate: u32, mut mix: impl FnMut(&[f64]) -> Vec<f32>) -> (f64, u64) {
+    const SECONDS: u32 = 4;
+    let start = Instant::now();
+    let mut checksum = 0u64;
+    let mut position = 0;
+    while position < (SECONDS * sample_rate) as u64 {
+        let time = LoopTunesBackend::time_chunk_at(position, LoopTunesBackend::FRAME_SIZE, sample_rate);
+        let samples = mix(&time);
+        for sample in samples {
+            checksum = checksum.rotate_left(5) ^ sample.to_bits() as u64;
+        }
+        position += LoopTunesBackend::FRAME_SIZE as u64;
+    }
+    (start.elapsed().as_secs_f64() * 1000.0 / SECONDS as f64, checksum)
+}
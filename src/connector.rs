@@ -1,19 +1,21 @@
 use std::f32::consts::{PI, TAU};
 
 use bevy::{ecs::system::EntityCommands, prelude::*};
+use bevy::input::mouse::MouseWheel;
 use bevy::sprite::Mesh2dHandle;
 
-use crate::{CommandsExt, Cycle, MousePos};
+use crate::{is_shift, soft_signum, CommandsExt, Cycle, Hover, Modulation, MousePos};
 
 pub struct ConnectorPlugin;
 
 impl Plugin for ConnectorPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(PreUpdate, arrow_sync_parent);
-        app.add_systems(Update, (connector_arrow_tracks_cursor, position_segment_mesh).chain());
+        app.add_systems(Update, (connector_arrow_tracks_cursor, position_segment_mesh, color_segments, hover_segment, scroll_segment).chain());
         app.add_systems(SpawnScene, (create_segment_mesh, create_bow_sprite, create_arrow_sprite));
         app.add_systems(Last, clear_orphaned_segments);
         app.insert_resource(Connector(None));
+        app.insert_resource(SegmentHover(None));
     }
 }
 
@@ -45,6 +47,9 @@ impl Segment {
 
 #[derive(Resource)] pub struct Connector(pub Option<Entity>);
 
+/** The child cycle of the connection the mouse is on, if it is not on a circle. */
+#[derive(Resource)] pub struct SegmentHover(pub Option<Entity>);
+
 fn create_segment_mesh(
     mut commands: Commands,
     q: Query<Entity,(With<Segment>,Without<Mesh2dHandle>)>,
@@ -126,9 +131,9 @@ fn position_segment_mesh(
         // Skip positioning connector if scale of bow and arrow has not yet propagated.
         if arrow_global.affine().x_axis.length_squared() < f32::EPSILON || bow_global.affine().x_axis.length_squared() < f32::EPSILON {continue}
 
-        // Position connector
+        // Position connector, subtle modulations get thinner lines.
         let length = Vec2::distance(target, source);
-        let width = f32::min(source_size, target_size) * LINE_WIDTH;
+        let width = f32::min(source_size, target_size) * LINE_WIDTH * (0.25 + 0.75 * f32::min(child_cycle.depth.abs(), 1.0));
         *q_transform.get_mut(segment_entity).unwrap() = Transform{
             scale: Vec3::new(length, width, 1.0),
             rotation: Quat::from_rotation_z(Vec2::to_angle(target - source)),
@@ -137,6 +142,80 @@ fn position_segment_mesh(
     }
 }
 
+/** Connections are colored by how the parent affects the child. */
+fn modulation_color(modulation: Modulation) -> Color {
+    match modulation {
+        Modulation::Amplitude => Color::WHITE,
+        Modulation::Frequency => Color::srgb(0.3, 0.7, 1.0),
+        Modulation::Phase => Color::srgb(0.4, 1.0, 0.4),
+        Modulation::Ring => Color::srgb(1.0, 0.6, 0.2),
+    }
+}
+
+fn color_segments(
+    q_segment: Query<(&Segment, &Handle<ColorMaterial>)>,
+    q_cycle: Query<&Cycle>,
+    mut q_sprite: Query<&mut Sprite>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    for (segment, material) in q_segment.iter() {
+        let modulation = q_cycle.get(segment.child_cycle).map_or(default(), |cycle| cycle.modulation);
+        let color = modulation_color(modulation);
+        for entity in [segment.bow, segment.arrow] {
+            let Ok(mut sprite) = q_sprite.get_mut(entity) else {continue};
+            if sprite.color != color {
+                sprite.color = color;
+            }
+        }
+        if materials.get(material).is_some_and(|material| material.color != color) {
+            materials.get_mut(material).unwrap().color = color;
+        }
+    }
+}
+
+fn hover_segment(
+    q_segment: Query<(&Segment, &Transform)>,
+    connector: Res<Connector>,
+    hover: Res<Hover>,
+    mouse: Res<MousePos>,
+    mut segment_hover: ResMut<SegmentHover>,
+) {
+    let mut found = None;
+    if hover.entity.is_none() && connector.0.is_none() && mouse.on_screen {
+        for (segment, transform) in q_segment.iter() {
+            if segment.parent_cycle.is_none() {continue}
+            // Lines are thin, so allow some pixels around them.
+            let local = transform.rotation.inverse() * (mouse.position.extend(0.0) - transform.translation);
+            let margin = f32::max(0.5 * transform.scale.y, 5.0 * mouse.zoom);
+            if local.x.abs() <= 0.5 * transform.scale.x && local.y.abs() <= margin {
+                found = Some(segment.child_cycle);
+                break
+            }
+        }
+    }
+    if segment_hover.0 != found {
+        segment_hover.0 = found;
+    }
+}
+
+/** Scrolling over a connection changes its depth, or its offset while holding shift. */
+fn scroll_segment(
+    mut q_cycles: Query<&mut Cycle>,
+    segment_hover: Res<SegmentHover>,
+    mut scroll: EventReader<MouseWheel>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    let Some(entity) = segment_hover.0 else {return};
+    let Ok(mut cycle) = q_cycles.get_mut(entity) else {return};
+    for event in scroll.read() {
+        if is_shift(&keyboard) {
+            cycle.change_offset(soft_signum(event.y));
+        } else {
+            cycle.change_depth(soft_signum(event.y));
+        }
+    }
+}
+
 fn create_bow_sprite(
     mut commands: Commands,
     q: Query<Entity,(With<Bow>,Without<Sprite>)>,
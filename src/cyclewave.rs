@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use bevy::app::App;
 use bevy::asset::Asset;
 use bevy::color::LinearRgba;
@@ -9,8 +11,9 @@ use bevy::render::render_resource::{AsBindGroup, BlendComponent, BlendFactor, Bl
 use bevy::sprite::{Anchor, Material2d, Material2dKey, Material2dPlugin, MaterialMesh2dBundle, Mesh2dHandle};
 
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 
-use crate::{is_shift, CommandsExt, Hover, LoopTunesBackend};
+use crate::{is_shift, BandLimited, ChildCycles, CommandsExt, Hover, Interpolation, LaunchQuantize, LoopPeriod, LoopTunesBackend, Modulation, QueuedLaunch, Tuning};
 
 pub struct CycleWavePlugin;
 impl Plugin for CycleWavePlugin {
@@ -18,7 +21,7 @@ impl Plugin for CycleWavePlugin {
         app 
             .add_plugins(Material2dPlugin::<WaveMaterial>::default())
             .add_systems(SpawnScene, (update_textures, create_children).chain())
-            .add_systems(Update, (update_frequency, toggle_play, rotate_cyclewaves).chain())
+            .add_systems(Update, (follow_relative_pitch, update_frequency, toggle_play, rotate_cyclewaves).chain())
             .add_systems(PostUpdate, clean_orphans)
         ;
     }
@@ -26,14 +29,60 @@ impl Plugin for CycleWavePlugin {
 
 #[derive(Component,Clone)] pub struct Playing;
 
+/**
+ * Whether the note of a cycle follows that of its parent or root, with an offset in steps of the frequency list.
+ * Only notes are followed, a parent that is not a note passes on the note of its own parent.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Relative {
+    #[default] Absolute,
+    Parent(i32),
+    Root(i32),
+}
+
+impl Relative {
+    fn shifted(self, steps: i32) -> Self {
+        match self {
+            Relative::Absolute => Relative::Absolute,
+            Relative::Parent(offset) => Relative::Parent(offset + steps),
+            Relative::Root(offset) => Relative::Root(offset + steps),
+        }
+    }
+}
+
 /**
  * Component that describes an audio cycle. 
  */
 #[derive(Component,Clone)]
 pub struct Cycle {
     pub frequency: u32,
+    /** Replaces the frequency from the list, in Hz. */
+    pub hz: Option<f64>,
+    /** Multiplies the frequency by p/q, e.g. 3/2 turns a rhythm into triplets. */
+    pub ratio: (u32, u32),
+    /** Detunes the frequency, in hundredths of a semitone. */
+    pub cents: f32,
+    /** Replaces the notes of the frequency list, None for 12-TET at A4=440. */
+    pub tuning: Option<Arc<Tuning>>,
+    /** Keeps the frequency at an offset from the parent or root, such that transposing them carries this cycle along. */
+    pub relative: Relative,
     pub phase: f32,
     pub color: LinearRgba,
+    pub interpolation: Interpolation,
+    /** Volume of this cycle and its children, in decibels. */
+    pub gain_db: f32,
+    /** From -1 (left) to 1 (right), relative to the parent. */
+    pub pan: f32,
+    /** Whether the parent's pattern drives the pan instead of the volume. */
+    pub auto_pan: bool,
+    /** How the parent's pattern affects this cycle. */
+    pub modulation: Modulation,
+    /** Scales the parent's pattern, which is -1..1 for all but amplitude modulation. Negative depths invert it. */
+    pub depth: f32,
+    /** Added to the scaled pattern, e.g. to keep a cycle partially audible while its parent is low. */
+    pub offset: f32,
+    /** Removes DC by subtracting the pattern's average, which songs made before the DC blocker rely on. */
+    pub legacy_dc: bool,
 }
 
 impl Cycle {
@@ -51,14 +100,31 @@ impl Cycle {
         ("C7",2093.004522404789), ("C#7",2217.4610478149766), ("D7",2349.31814333926), ("D#7",2489.0158697766474), ("E7",2637.02045530296), ("F7",2793.825851464031), ("F#7",2959.955381693075), ("G7",3135.9634878539946), ("G#7",3322.437580639561), ("A7",3520.0), ("A#7",3729.3100921447194), ("B7",3951.066410048992), 
         ("C8",4186.009044809578), ("C#8",4434.922095629953), ("D8",4698.63628667852), ("D#8",4978.031739553295), ("E8",5274.04091060592), ("F8",5587.651702928062), ("F#8",5919.91076338615), ("G8",6271.926975707989), ("G#8",6644.875161279122), ("A8",7040.0), ("A#8",7458.620184289437), ("B8",7902.132820097988), 
     ];
+    /** Number of rhythm entries before the notes in the frequency list. */
+    const RHYTHMS : usize = 25;
     pub const DEFAULT_FREQUENCY : u32 = 16;
     pub const NOTE_A4 : u32 = 82;
 
     pub fn frequency(&self) -> f64 {
-        Self::FREQUENCY_LIST[self.frequency as usize].1
+        let base = self.hz.unwrap_or(self.list_entry().1);
+        let (p, q) = self.ratio;
+        base * p as f64 / q as f64 * f64::powf(2.0, self.cents as f64 / 1200.0)
+    }
+    pub fn frequency_name(&self) -> &str {
+        self.list_entry().0
     }
-    pub fn frequency_name(&self) -> &'static str {
-        Self::FREQUENCY_LIST[self.frequency as usize].0
+    fn list_entry(&self) -> (&str, f64) {
+        let index = self.frequency as usize;
+        match &self.tuning {
+            Some(tuning) if index >= Self::RHYTHMS => tuning.note(index - Self::RHYTHMS),
+            _ => Self::FREQUENCY_LIST[index],
+        }
+    }
+    fn list_len(&self) -> usize {
+        match &self.tuning {
+            Some(tuning) => Self::RHYTHMS + tuning.note_count(),
+            None => Self::FREQUENCY_LIST.len(),
+        }
     }
     pub fn scale(&self) -> f32 {
         f32::max(2. / self.frequency().sqrt().sqrt() as f32, 1.)
@@ -67,8 +133,45 @@ impl Cycle {
     pub fn phase_in_parent(&self) -> f32 {
         self.phase
     }
+    pub fn gain(&self) -> f32 {
+        f32::powf(10.0, self.gain_db / 20.0)
+    }
+    pub fn change_gain(&mut self, lines: f32) {
+        self.gain_db = (self.gain_db + lines).clamp(-48.0, 12.0);
+    }
+    /** Changes the depth in steps of 5%. */
+    pub fn change_depth(&mut self, lines: f32) {
+        self.depth = ((self.depth + 0.05 * lines) * 20.0).round().clamp(-20.0, 20.0) / 20.0;
+    }
+    /** Changes the offset in steps of 5%. */
+    pub fn change_offset(&mut self, lines: f32) {
+        self.offset = ((self.offset + 0.05 * lines) * 20.0).round().clamp(-20.0, 20.0) / 20.0;
+    }
+    /** Changes the detune in steps of one cent, up to a semitone. */
+    pub fn change_cents(&mut self, lines: f32) {
+        self.cents = (self.cents + lines).round().clamp(-100.0, 100.0);
+    }
     pub fn change_frequency(&mut self, lines: i32) {
-        self.frequency = (self.frequency as i32 + lines).clamp(0, Self::FREQUENCY_LIST.len() as i32 - 1) as u32;
+        // Scrolling returns to the list.
+        self.hz = None;
+        self.set_frequency((self.frequency as i32 + lines).clamp(0, self.list_len() as i32 - 1) as u32);
+    }
+    /** Whether the frequency is one of the notes of the list, rather than a rhythm or an exact frequency. */
+    pub fn is_note(&self) -> bool {
+        self.hz.is_none() && self.frequency as usize >= Self::RHYTHMS
+    }
+    /** The list entry of the note, None if the cycle plays a rhythm or an exact frequency. */
+    pub fn note(&self) -> Option<i32> {
+        self.is_note().then_some(self.frequency as i32)
+    }
+    /** Moves a note up or down, without leaving the notes. */
+    pub fn transpose(&mut self, steps: i32) {
+        self.set_frequency((self.frequency as i32 + steps).clamp(Self::RHYTHMS as i32, self.list_len() as i32 - 1) as u32);
+    }
+    /** Changes the list entry, relative cycles keep following their reference at the new offset. */
+    fn set_frequency(&mut self, frequency: u32) {
+        self.relative = self.relative.shifted(frequency as i32 - self.frequency as i32);
+        self.frequency = frequency;
     }
 }
 
@@ -76,8 +179,21 @@ impl Default for Cycle {
     fn default() -> Self {
         Self {
             frequency: Self::DEFAULT_FREQUENCY,
+            hz: None,
+            ratio: (1, 1),
+            cents: 0.0,
+            tuning: None,
+            relative: default(),
             phase: 0.0,
             color: LinearRgba::WHITE,
+            interpolation: default(),
+            gain_db: 0.0,
+            pan: 0.0,
+            auto_pan: false,
+            modulation: default(),
+            depth: 1.0,
+            offset: 0.0,
+            legacy_dc: false,
         }
     }
 }
@@ -145,45 +261,166 @@ fn clean_orphans(
 }
 
 fn update_frequency(
-    mut q_cycle: Query<Ref<Cycle>>,
+    q_cycle: Query<(Ref<Cycle>, Option<&Parent>)>,
+    q_children: Query<&ChildCycles>,
     mut q_text: Query<(&mut Text, &mut Transform, &Parent)>,
 ) {
     for (mut text, mut transform, parent) in q_text.iter_mut() {
-        let Ok(cycle) = q_cycle.get_mut(parent.get()) else {continue};
+        let Ok((cycle, cycle_parent)) = q_cycle.get(parent.get()) else {continue};
+        let mut label = match cycle.hz {
+            Some(hz) => format!("{}Hz", hz),
+            None => String::from(cycle.frequency_name()),
+        };
+        if cycle.ratio != (1, 1) {
+            label += &format!("\nratio {}/{}", cycle.ratio.0, cycle.ratio.1);
+        }
+        if cycle.cents != 0.0 {
+            label += &format!("\n{:+} cents", cycle.cents);
+        }
+        match cycle.relative {
+            _ if cycle_parent.is_none() => (),
+            Relative::Absolute => (),
+            Relative::Parent(offset) => label += &format!("\nparent {:+}", offset),
+            Relative::Root(offset) => label += &format!("\nroot {:+}", offset),
+        }
+        if cycle.interpolation != Interpolation::Nearest {
+            label += "\n";
+            label += cycle.interpolation.name();
+        }
+        if cycle.gain_db != 0.0 {
+            label += &format!("\n{:+} dB", cycle.gain_db);
+        }
+        if cycle.auto_pan {
+            label += "\nauto-pan";
+        }
+        if cycle.modulation != Modulation::Amplitude && cycle_parent.is_some() {
+            label += "\n";
+            label += cycle.modulation.name();
+        }
+        if cycle.depth != 1.0 {
+            label += &format!("\ndepth {}%", (cycle.depth * 100.0).round());
+        }
+        if cycle.offset != 0.0 {
+            label += &format!("\noffset {:+}%", (cycle.offset * 100.0).round());
+        }
+        if cycle.pan != 0.0 {
+            let side = if cycle.pan < 0.0 {"L"} else {"R"};
+            label += &format!("\npan {}{}", side, (cycle.pan.abs() * 100.0).round());
+        }
+        if cycle_parent.is_none() && q_children.contains(parent.get()) {
+            if let Some(period) = tree_period(parent.get(), &q_cycle, &q_children) {
+                label += "\nloop ";
+                label += &period.name();
+            }
+        }
+        if text.sections[0].value != label {
+            text.sections[0].value = label;
+        }
         if cycle.is_changed() {
-            text.sections[0].value = cycle.frequency_name().into();
             let scale = cycle.scale() * TEXT_SCALE;
             transform.scale = Vec3::new(scale, scale, 1.0);
         }
     }
 }
 
+/** Moves relative cycles along with their parent or root note, from the roots down such that chains of relative cycles follow in one frame. */
+fn follow_relative_pitch(
+    q_roots: Query<Entity, (With<Cycle>, Without<Parent>)>,
+    q_children: Query<&ChildCycles>,
+    mut q_cycles: Query<&mut Cycle>,
+) {
+    for root in q_roots.iter() {
+        let Ok(root_note) = q_cycles.get(root).map(Cycle::note) else {continue};
+        // Stack of nodes with the nearest note at or above them.
+        let mut stack = vec![(root, root_note)];
+        while let Some((node, note)) = stack.pop() {
+            if let Ok(children) = q_children.get(node) {
+                for &child in children.0.iter() {
+                    let Ok(mut cycle) = q_cycles.get_mut(child) else {continue};
+                    let target = match cycle.relative {
+                        Relative::Absolute => None,
+                        Relative::Parent(offset) => note.map(|note| note + offset),
+                        Relative::Root(offset) => root_note.map(|note| note + offset),
+                    };
+                    if let Some(target) = target {
+                        let target = target.clamp(Cycle::RHYTHMS as i32, cycle.list_len() as i32 - 1) as u32;
+                        if cycle.frequency != target {
+                            cycle.frequency = target;
+                        }
+                    }
+                    stack.push((child, cycle.note().or(note)));
+                }
+            }
+        }
+    }
+}
+
+/** Computes the repeat period of the tree starting at the given root. */
+fn tree_period(
+    root: Entity,
+    q_cycle: &Query<(Ref<Cycle>, Option<&Parent>)>,
+    q_children: &Query<&ChildCycles>,
+) -> Option<LoopPeriod> {
+    let mut frequencies = Vec::new();
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        let Ok((cycle, _)) = q_cycle.get(node) else {continue};
+        frequencies.push(cycle.frequency());
+        if let Ok(children) = q_children.get(node) {
+            stack.extend(children.0.iter());
+        }
+    }
+    LoopPeriod::of(frequencies)
+}
+
 fn toggle_play(
     mut commands: Commands,
     hover: Res<Hover>,
-    q_cycle: Query<Option<&Playing>, With<Cycle>>,
+    q_cycle: Query<(Option<&Playing>, Option<&QueuedLaunch>), With<Cycle>>,
     q_children: Query<&Children, With<Cycle>>,
     keyboard: Res<ButtonInput<KeyCode>>,
+    quantize: Res<LaunchQuantize>,
+    backend: Res<LoopTunesBackend>,
 ) {
     if !keyboard.just_pressed(KeyCode::Space) {return}
     let Some(hover_entity) = hover.entity else {return};
-    let new_play_state = q_cycle.get(hover_entity).unwrap().is_none();
+    let (playing, queued) = q_cycle.get(hover_entity).unwrap();
+    // Pressing space again cancels a queued launch.
+    if queued.is_some() {
+        commands.entity(hover_entity).remove::<QueuedLaunch>();
+        return
+    }
+    let play = playing.is_none();
+    let tree = is_shift(&keyboard);
+
+    // If nothing plays, playback starts from the start position, so there is nothing to wait for.
+    let now = backend.elapsed_seconds();
+    let boundary = if backend.scene().roots.is_empty() {None} else {quantize.next_boundary(now)};
+    match boundary {
+        Some(at) => {
+            commands.entity(hover_entity).insert(QueuedLaunch{play, tree, at, queued: now});
+        },
+        None => set_play_state(&mut commands, &q_children, hover_entity, play, tree),
+    }
+}
+
+/** Starts or stops playing the cycle, and also its descendants if tree is set. */
+pub fn set_play_state(commands: &mut Commands, q_children: &Query<&Children, With<Cycle>>, entity: Entity, play: bool, tree: bool) {
     let mut set_state = |entity: Entity| {
         let Some(mut ec) = commands.get_entity(entity) else {return};
-        if new_play_state {
+        if play {
             ec.insert(Playing);
         } else {
             ec.remove::<Playing>();
         }
     };
-    set_state(hover_entity);
+    set_state(entity);
 
-    if is_shift(&keyboard) {
-        for descendant in q_children.iter_descendants(hover_entity) {
+    if tree {
+        for descendant in q_children.iter_descendants(entity) {
             set_state(descendant);
         }
     }
-    
 }
 
 #[derive(Component,Clone)]
@@ -198,10 +435,12 @@ fn rotate_cyclewaves(
     for (parent, mut transform) in q_child.iter_mut() {
         let Ok((cycle, playing)) = q_parent.get(parent.get()) else {continue};
         let playing = playing.is_some() && Some(parent.get()) != hover.entity;
-        let frequency = if playing {cycle.frequency() as f32} else {0.0};
+        let frequency = if playing {cycle.frequency()} else {0.0};
         let scale = cycle.scale() * 2.0;
         transform.scale = Vec3::new(scale, scale, 1.0);
-        transform.rotation = Quat::from_rotation_z(-std::f32::consts::TAU * time.elapsed_seconds() * frequency);
+        // Take the fraction in f64, such that the rotation stays accurate during long sessions.
+        let turns = (time.elapsed_seconds() * frequency).fract() as f32;
+        transform.rotation = Quat::from_rotation_z(-std::f32::consts::TAU * turns);
     }
 }
     
@@ -214,6 +453,8 @@ pub struct Wave {
     pub pattern: [f32;Self::LENGTH],
     pub material: Handle<WaveMaterial>,
     pub average: f32,
+    /** Generated by update_textures, whenever the pattern changes. */
+    pub band_limited: Arc<BandLimited>,
 }
 
 impl Wave {
@@ -233,6 +474,7 @@ impl Wave {
             pattern: r,
             material: default(),
             average: 0.0,
+            band_limited: default(),
         }
     }
 }
@@ -243,6 +485,7 @@ impl Default for Wave {
             pattern: [0.0; 1024],
             material: default(),
             average: 0.0,
+            band_limited: default(),
         }
     }
 }
@@ -258,6 +501,8 @@ fn update_textures(
             wave.material = materials.add(WaveMaterial::new(LinearRgba::WHITE, default()));
         }
         if item.is_changed() {
+            // Not bypassing change detection here, such that the scene is published again.
+            item.band_limited = Arc::new(BandLimited::new(&item.pattern));
             let wave = &mut item.bypass_change_detection();
             wave.average = wave.pattern.iter().sum::<f32>() / 1024.0;
             fn f32_to_u8(v: &f32) -> u8 {
@@ -0,0 +1,130 @@
+use bevy::input::keyboard::{Key, KeyboardInput};
+use bevy::prelude::*;
+
+use crate::{println, Cycle, Hover, Hud};
+
+pub struct FrequencyEntryPlugin;
+
+impl Plugin for FrequencyEntryPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(FrequencyEntry::default())
+            .add_systems(Startup, spawn_entry_readout)
+            .add_systems(Update, (type_frequency, update_entry_readout).chain());
+    }
+}
+
+/**
+ * Frequency that is being typed for a circle.
+ * Press enter above a circle, type a frequency in Hz like 441.5 or a ratio like 5/4, and press enter again.
+ */
+#[derive(Resource, Default)]
+pub struct FrequencyEntry {
+    target: Option<Entity>,
+    text: String,
+}
+
+/** Stops the digit keys from adding circles while a frequency is being typed. */
+pub fn is_entering_frequency(entry: Res<FrequencyEntry>) -> bool {
+    entry.target.is_some()
+}
+
+enum Pitch {
+    Hz(f64),
+    Ratio(u32, u32),
+}
+
+impl Pitch {
+    const MAX_HZ: f64 = 20000.0;
+
+    fn parse(text: &str) -> Option<Pitch> {
+        if let Some((p, q)) = text.split_once('/') {
+            let p: u32 = p.parse().ok()?;
+            let q: u32 = q.parse().ok()?;
+            if p == 0 || q == 0 {return None}
+            let divisor = gcd(p, q);
+            return Some(Pitch::Ratio(p / divisor, q / divisor));
+        }
+        let hz: f64 = text.parse().ok()?;
+        if !(hz > 0.0 && hz <= Self::MAX_HZ) {return None}
+        Some(Pitch::Hz(hz))
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {a} else {gcd(b, a % b)}
+}
+
+fn type_frequency(
+    mut entry: ResMut<FrequencyEntry>,
+    mut q_cycles: Query<&mut Cycle>,
+    hover: Res<Hover>,
+    mut keys: EventReader<KeyboardInput>,
+) {
+    for event in keys.read() {
+        if !event.state.is_pressed() {continue}
+        let Some(target) = entry.target else {
+            if event.logical_key == Key::Enter && hover.entity.is_some() {
+                entry.target = hover.entity;
+                entry.text.clear();
+            }
+            continue
+        };
+        match &event.logical_key {
+            Key::Character(text) if text.chars().all(|c| c.is_ascii_digit() || c == '.' || c == '/') => {
+                entry.text.push_str(text);
+            },
+            Key::Backspace => {
+                entry.text.pop();
+            },
+            Key::Escape => {
+                entry.target = None;
+            },
+            Key::Enter => {
+                entry.target = None;
+                let Ok(mut cycle) = q_cycles.get_mut(target) else {continue};
+                // An empty entry returns to the frequency list.
+                if entry.text.is_empty() {
+                    cycle.hz = None;
+                    cycle.ratio = (1, 1);
+                    continue
+                }
+                match Pitch::parse(&entry.text) {
+                    Some(Pitch::Hz(hz)) => {
+                        cycle.hz = Some(hz);
+                        cycle.ratio = (1, 1);
+                        cycle.cents = 0.0;
+                    },
+                    Some(Pitch::Ratio(p, q)) => {
+                        let previous = std::mem::replace(&mut cycle.ratio, (p, q));
+                        if cycle.frequency() > Pitch::MAX_HZ {
+                            println!("Ratio {}/{} gives a frequency above {}Hz", p, q, Pitch::MAX_HZ);
+                            cycle.ratio = previous;
+                        }
+                    },
+                    None => println!("Not a frequency or ratio: {}", entry.text),
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+#[derive(Component)] struct EntryReadout;
+
+fn spawn_entry_readout(mut commands: Commands) {
+    Hud::spawn(&mut commands, Vec2::new(0.0, 1.0), 0, Color::srgb(0.7, 0.7, 0.7), "")
+        .insert(EntryReadout);
+}
+
+fn update_entry_readout(
+    entry: Res<FrequencyEntry>,
+    mut q_readout: Query<&mut Text, With<EntryReadout>>,
+) {
+    if !entry.is_changed() {return}
+    let Ok(mut text) = q_readout.get_single_mut() else {return};
+    text.sections[0].value = match entry.target {
+        Some(_) => format!("frequency (Hz or ratio): {}_", entry.text),
+        None => String::new(),
+    };
+}
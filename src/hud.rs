@@ -0,0 +1,60 @@
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use bevy::sprite::Anchor;
+use bevy::transform::TransformSystem;
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostUpdate, position_hud.before(TransformSystem::TransformPropagate));
+    }
+}
+
+/**
+ * Text that stays at a fixed position on the screen.
+ * The anchor is in -1..1 screen coordinates, e.g. (-1,1) is the top-left corner.
+ * Lines are stacked from the anchor towards the center of the screen.
+ */
+#[derive(Component)]
+pub struct Hud {
+    pub anchor: Vec2,
+    pub line: u32,
+}
+
+const FONT_SIZE: f32 = 20.0;
+const MARGIN: f32 = 10.0;
+const DEPTH: f32 = 10.0;
+
+impl Hud {
+    pub fn spawn<'a>(commands: &'a mut Commands, anchor: Vec2, line: u32, color: Color, text: impl Into<String>) -> EntityCommands<'a> {
+        commands.spawn((
+            Text2dBundle{
+                text: Text::from_section(text, TextStyle{
+                    font_size: FONT_SIZE,
+                    color,
+                    ..default()
+                }),
+                text_anchor: Anchor::Custom(anchor * 0.5),
+                ..default()
+            },
+            Hud{anchor, line},
+        ))
+    }
+}
+
+fn position_hud(
+    q_camera: Query<&Transform, (With<Camera2d>, Without<Hud>)>,
+    windows: Query<&Window>,
+    mut q_hud: Query<(&Hud, &mut Transform)>,
+) {
+    let Ok(camera) = q_camera.get_single() else {return};
+    let Ok(window) = windows.get_single() else {return};
+    let half_size = 0.5 * Vec2::new(window.width(), window.height()) - MARGIN;
+    for (hud, mut transform) in q_hud.iter_mut() {
+        let line_offset = -hud.anchor.y.signum() * hud.line as f32 * FONT_SIZE;
+        let position = hud.anchor * half_size + Vec2::new(0.0, line_offset);
+        transform.translation = camera.translation + position.extend(DEPTH) * camera.scale;
+        transform.scale = camera.scale;
+    }
+}
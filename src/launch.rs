@@ -0,0 +1,174 @@
+use bevy::prelude::*;
+use bevy::sprite::Mesh2dHandle;
+
+use crate::{set_play_state, CommandsExt, Cycle, Hud, LoopTunesBackend};
+
+pub struct LaunchPlugin;
+
+impl Plugin for LaunchPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(LaunchQuantize::default())
+            .add_systems(Startup, (setup_countdown, spawn_quantize_readout))
+            .add_systems(Update, (change_launch_quantize, update_quantize_readout).chain())
+            .add_systems(Update, (requeue_after_seek, launch_queued, update_countdown).chain());
+    }
+}
+
+/**
+ * Length of the cycle on whose boundaries circles start and stop playing, like a clip launcher.
+ * None starts and stops them right away.
+ */
+#[derive(Resource, Default)]
+pub struct LaunchQuantize {
+    pub length: Option<f64>,
+}
+
+impl LaunchQuantize {
+    const LENGTHS: [Option<f64>; 6] = [None, Some(1.0), Some(2.0), Some(4.0), Some(8.0), Some(16.0)];
+
+    /** Time of the first boundary at or after the given time, in seconds. */
+    pub fn next_boundary(&self, seconds: f64) -> Option<f64> {
+        self.length.map(|length| (seconds / length).ceil() * length)
+    }
+
+    fn next(&self) -> Option<f64> {
+        let index = Self::LENGTHS.iter().position(|&length| length == self.length).map_or(0, |i| (i + 1) % Self::LENGTHS.len());
+        Self::LENGTHS[index]
+    }
+}
+
+/** Change of the play state that waits for the next boundary. */
+#[derive(Component)]
+pub struct QueuedLaunch {
+    pub play: bool,
+    /** Whether the descendants change along with the cycle. */
+    pub tree: bool,
+    /** Time of the boundary, in seconds. */
+    pub at: f64,
+    /** Time at which the launch was queued, such that the countdown can show the remaining part. */
+    pub queued: f64,
+}
+
+#[derive(Component)] struct QuantizeReadout;
+
+fn spawn_quantize_readout(mut commands: Commands) {
+    Hud::spawn(&mut commands, Vec2::new(-1.0, -1.0), 1, Color::srgb(0.7, 0.7, 0.7), "")
+        .insert(QuantizeReadout);
+}
+
+/** Switches between the launch quantization lengths with Q. */
+fn change_launch_quantize(
+    mut quantize: ResMut<LaunchQuantize>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if keyboard.just_pressed(KeyCode::KeyQ) {
+        quantize.length = quantize.next();
+    }
+}
+
+fn update_quantize_readout(
+    quantize: Res<LaunchQuantize>,
+    mut q_readout: Query<&mut Text, With<QuantizeReadout>>,
+) {
+    if !quantize.is_changed() {return}
+    let Ok(mut text) = q_readout.get_single_mut() else {return};
+    text.sections[0].value = match quantize.length {
+        Some(length) => format!("launch every {}s", length),
+        None => "launch right away".into(),
+    };
+}
+
+/** Moves queued launches to the first boundary after a seek, as the time they waited for no longer comes up as expected. */
+fn requeue_after_seek(
+    backend: Res<LoopTunesBackend>,
+    quantize: Res<LaunchQuantize>,
+    mut q_queued: Query<&mut QueuedLaunch>,
+    mut seeks: Local<u64>,
+) {
+    if *seeks == backend.seek_count() {return}
+    *seeks = backend.seek_count();
+    let now = backend.seek_position();
+    for mut launch in q_queued.iter_mut() {
+        launch.at = quantize.next_boundary(now).unwrap_or(now);
+        launch.queued = now;
+    }
+}
+
+/**
+ * Applies queued launches slightly before their boundary.
+ * The backend holds the published scene back until the boundary, such that it starts at the exact sample.
+ */
+fn launch_queued(
+    mut commands: Commands,
+    mut backend: ResMut<LoopTunesBackend>,
+    q_queued: Query<(Entity, &QueuedLaunch)>,
+    q_children: Query<&Children, With<Cycle>>,
+) {
+    let now = backend.elapsed_seconds() + backend.lead_seconds();
+    for (entity, launch) in q_queued.iter() {
+        if now < launch.at {continue}
+        set_play_state(&mut commands, &q_children, entity, launch.play, launch.tree);
+        commands.entity(entity).remove::<QueuedLaunch>();
+        backend.launch_at(launch.at);
+    }
+}
+
+#[derive(Resource)]
+struct CountdownAssets {
+    mesh: Mesh2dHandle,
+    play: Handle<ColorMaterial>,
+    stop: Handle<ColorMaterial>,
+}
+
+/** Ring that shrinks onto a queued circle, reaching it at the boundary. */
+#[derive(Component)] struct Countdown;
+
+fn setup_countdown(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    commands.insert_resource(CountdownAssets{
+        mesh: Mesh2dHandle(meshes.add(Annulus::new(1.12, 1.16).mesh().resolution(32))),
+        play: materials.add(ColorMaterial::from_color(Color::srgb(0.3, 1.0, 0.3))),
+        stop: materials.add(ColorMaterial::from_color(Color::srgb(1.0, 0.3, 0.3))),
+    });
+}
+
+fn update_countdown(
+    mut commands: Commands,
+    assets: Res<CountdownAssets>,
+    backend: Res<LoopTunesBackend>,
+    q_added: Query<(Entity, &Cycle, &QueuedLaunch), Added<QueuedLaunch>>,
+    q_queued: Query<(&Cycle, &QueuedLaunch)>,
+    mut q_rings: Query<(Entity, Option<&Parent>, &mut Transform), With<Countdown>>,
+) {
+    for (entity, cycle, launch) in q_added.iter() {
+        let material = if launch.play {assets.play.clone()} else {assets.stop.clone()};
+        let scale = cycle.scale() * 2.0;
+        commands.entity(entity).with_children(|parent| {
+            parent.spawn((
+                ColorMesh2dBundle{
+                    mesh: assets.mesh.clone(),
+                    material,
+                    transform: Transform::from_scale(Vec3::new(scale, scale, 1.0)),
+                    ..default()
+                },
+                Countdown,
+            ));
+        });
+    }
+
+    let now = backend.elapsed_seconds();
+    for (ring, parent, mut transform) in q_rings.iter_mut() {
+        // Remove the ring once the launch happened, was cancelled or the circle was deleted.
+        let Some((cycle, launch)) = parent.and_then(|parent| q_queued.get(parent.get()).ok()) else {
+            commands.try_despawn(ring);
+            continue
+        };
+        let remaining = ((launch.at - now) / (launch.at - launch.queued).max(1e-3)).clamp(0.0, 1.0) as f32;
+        let scale = cycle.scale() * (1.0 + remaining);
+        transform.scale = Vec3::new(scale, scale, 1.0);
+    }
+}
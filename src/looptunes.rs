@@ -1,88 +1,716 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use bevy::app::{App, Plugin};
 use bevy::prelude::*;
+use bevy::tasks::TaskPool;
+use bevy::utils::Instant;
 
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::cpal;
 use rodio::source::SeekError;
 use rodio::{OutputStream, Sink, Source};
 
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
-use crate::println;
+use crate::{println, Arena, Hud, MasterBus, Scene};
 
-pub struct LoopTunesPlugin;
+pub struct LoopTunesPlugin {
+    /** Use the null backend, even if an audio device is available. */
+    pub null_audio: bool,
+    /** Name of the output device, or None for the default device. */
+    pub device: Option<String>,
+    pub sample_rate: u32,
+    pub latency: Latency,
+    /** Length of the ramps when the scene changes, in seconds. */
+    pub fade: f32,
+}
 
 impl Plugin for LoopTunesPlugin {
     fn build(&self, app: &mut App) {
         println!("Enabling LoopTunes audio backend Plugin!");
-        // Init rodio
-        let (stream, stream_handle) = OutputStream::try_default().unwrap();
-        
-        // Create a channel
-        let (tx,rx) = bounded::<f32>(LoopTunesBackend::BUFFER);
-        let source = LoopSource{consumer: rx, last: 0.0};
-        
-        // Get something we can send audio to.
-        let sink = Sink::try_new(&stream_handle).unwrap();
-        sink.append(source);
-
-        app.insert_resource(LoopTunesBackend{
-            producer: tx,
-            position: 0,
-        });
-        // Keep stream & sink alive for the duration of the application.
-        app.insert_non_send_resource(LoopTunesInternal{
-            stream,
-            sink,
+        // The output is opened by apply_audio_settings, until then samples are discarded.
+        app.insert_resource(AudioSettings{
+            null_audio: self.null_audio,
+            device: self.device.clone(),
+            sample_rate: self.sample_rate,
+            latency: self.latency,
         });
+        app.insert_resource(LoopTunesBackend::new(self.sample_rate, self.latency.samples(), self.fade));
+        app.insert_resource(MasterVolume{db: 0.0});
+        app.add_systems(Startup, (spawn_audio_notice, spawn_volume_readout, spawn_clip_indicator));
+        app.add_systems(First, apply_audio_settings.run_if(resource_changed::<AudioSettings>));
+        app.add_systems(Update, (select_audio_output, update_audio_notice, update_clip_indicator));
+        app.add_systems(Update, (change_master_volume, apply_master_volume.run_if(resource_changed::<MasterVolume>), update_volume_readout).chain());
+        app.add_systems(Last, drive_null_output);
+    }
+}
+
+/** Audio output requested by the user. Changing it reopens the output. */
+#[derive(Resource, Clone)]
+pub struct AudioSettings {
+    pub null_audio: bool,
+    pub device: Option<String>,
+    pub sample_rate: u32,
+    pub latency: Latency,
+}
+
+impl AudioSettings {
+    pub const SAMPLE_RATES: [u32; 3] = [44100, 48000, 96000];
+}
+
+/**
+ * Number of samples the audio thread renders at once.
+ * Changes to the tune are heard at the start of the next frame,
+ * but small frames are more likely to stutter on a busy machine.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Latency {
+    Low,
+    Normal,
+    Safe,
+    Samples(usize),
+}
+
+impl Latency {
+    pub fn samples(self) -> usize {
+        match self {
+            Latency::Low => 128,
+            Latency::Normal => 512,
+            Latency::Safe => 2048,
+            Latency::Samples(samples) => samples.clamp(16, 16384),
+        }
+    }
+
+    /** Parses "low", "normal", "safe" or a number of samples. */
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "low" => Some(Latency::Low),
+            "normal" => Some(Latency::Normal),
+            "safe" => Some(Latency::Safe),
+            _ => text.parse().ok().filter(|&samples| samples > 0).map(Latency::Samples),
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Latency::Low => Latency::Normal,
+            Latency::Normal => Latency::Safe,
+            Latency::Safe | Latency::Samples(_) => Latency::Low,
+        }
+    }
+}
+
+/** Names of the available audio output devices. */
+pub fn output_device_names() -> Vec<String> {
+    let Ok(devices) = cpal::default_host().output_devices() else {return default()};
+    devices.filter_map(|device| device.name().ok()).collect()
+}
+
+fn open_device(settings: &AudioSettings) -> Option<(String, LoopTunesInternal)> {
+    let host = cpal::default_host();
+    let device = match &settings.device {
+        Some(name) => host.output_devices().ok().and_then(|mut devices| devices.find(|device| device.name().ok().as_ref() == Some(name))),
+        None => host.default_output_device(),
+    };
+    let Some(device) = device else {
+        println!("Failed to open audio device: {}", settings.device.as_deref().unwrap_or("no default device"));
+        return None
+    };
+    let name = device.name().unwrap_or_default();
+
+    // Init rodio, preferably running the device at our sample rate. Otherwise rodio will resample.
+    let rate = settings.sample_rate;
+    let config = device.supported_output_configs().ok()
+        .and_then(|mut configs| configs.find(|config| config.min_sample_rate().0 <= rate && rate <= config.max_sample_rate().0))
+        .map(|config| config.with_sample_rate(cpal::SampleRate(rate)));
+    let stream = match config.map(|config| OutputStream::try_from_device_config(&device, config)) {
+        Some(Ok(ok)) => Ok(ok),
+        _ => OutputStream::try_from_device(&device),
+    };
+    let (stream, stream_handle) = match stream {
+        Ok(ok) => ok,
+        Err(err) => { println!("Failed to open audio device: {:?}", err); return None }
+    };
+
+    // Get something we can send audio to.
+    let sink = match Sink::try_new(&stream_handle) {
+        Ok(ok) => ok,
+        Err(err) => { println!("Failed to open audio device: {:?}", err); return None }
+    };
+
+    Some((name, LoopTunesInternal{
+        stream,
+        sink,
+    }))
+}
+
+/** (Re)opens the audio output whenever the settings change. */
+fn apply_audio_settings(world: &mut World) {
+    let settings = world.resource::<AudioSettings>().clone();
+
+    // Close the old stream first, as some devices can only be opened once.
+    world.remove_non_send_resource::<LoopTunesInternal>();
+    let device = if settings.null_audio {None} else {open_device(&settings)};
+
+    let mut backend = world.resource_mut::<LoopTunesBackend>();
+    backend.set_sample_rate(settings.sample_rate);
+    backend.frame_size = settings.latency.samples();
+    let (commands, source) = backend.new_source();
+    match device {
+        Some((name, internal)) => {
+            println!("Playing on {} at {} Hz", name, settings.sample_rate);
+            internal.sink.append(source);
+            backend.output = Output::Device{commands, name, opened: Instant::now()};
+            // Keep stream & sink alive until the output changes.
+            world.insert_non_send_resource(internal);
+        },
+        None => {
+            println!("No audio device, using null backend.");
+            backend.output = Output::Null{source: Box::new(source), start: Instant::now(), pulled: 0};
+        },
+    }
+}
+
+/** Cycles through the output devices with F2, the sample rates with F3 and the latencies with F4. */
+fn select_audio_output(
+    mut settings: ResMut<AudioSettings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if keyboard.just_pressed(KeyCode::F2) {
+        let names = output_device_names();
+        let next = match &settings.device {
+            None => names.first(),
+            Some(name) => names.iter().skip_while(|n| *n != name).nth(1),
+        };
+        settings.device = next.cloned();
+        settings.null_audio = false;
+    }
+    if keyboard.just_pressed(KeyCode::F3) {
+        let rates = AudioSettings::SAMPLE_RATES;
+        let index = rates.iter().position(|&rate| rate == settings.sample_rate).map_or(0, |i| (i + 1) % rates.len());
+        settings.sample_rate = rates[index];
+    }
+    if keyboard.just_pressed(KeyCode::F4) {
+        settings.latency = settings.latency.next();
+    }
+}
+
+#[derive(Component)] struct AudioNotice;
+
+fn spawn_audio_notice(mut commands: Commands) {
+    Hud::spawn(&mut commands, Vec2::new(1.0, 1.0), 0, Color::srgb(1.0, 0.6, 0.2), "")
+        .insert(AudioNotice);
+}
+
+/** Shows a notice if there is no audio device, and briefly shows the device after switching. */
+fn update_audio_notice(
+    backend: Res<LoopTunesBackend>,
+    mut q_notice: Query<&mut Text, With<AudioNotice>>,
+) {
+    let Ok(mut text) = q_notice.get_single_mut() else {return};
+    let label = match &backend.output {
+        Output::Null{..} => "no audio device".into(),
+        Output::Device{name, opened, ..} if opened.elapsed().as_secs_f32() < 3.0 => format!("{} @ {} Hz, {} ms", name, backend.sample_rate, 1000 * backend.frame_size as u32 / backend.sample_rate),
+        Output::Device{..} => String::new(),
+    };
+    if text.sections[0].value != label {
+        text.sections[0].value = label;
+    }
+}
+
+
+/** Volume of the whole mix, in decibels. */
+#[derive(Resource)]
+pub struct MasterVolume {
+    pub db: f32,
+}
+
+impl MasterVolume {
+    pub fn gain(&self) -> f32 {
+        f32::powf(10.0, self.db / 20.0)
+    }
+}
+
+/** Changes the master volume with the - and = keys. */
+fn change_master_volume(
+    mut volume: ResMut<MasterVolume>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    let step = match () {
+        _ if keyboard.just_pressed(KeyCode::Minus) || keyboard.just_pressed(KeyCode::NumpadSubtract) => -1.0,
+        _ if keyboard.just_pressed(KeyCode::Equal) || keyboard.just_pressed(KeyCode::NumpadAdd) => 1.0,
+        _ => return
+    };
+    volume.db = (volume.db + step).clamp(-60.0, 6.0);
+}
+
+fn apply_master_volume(
+    volume: Res<MasterVolume>,
+    mut backend: ResMut<LoopTunesBackend>,
+) {
+    backend.set_volume(volume.gain());
+}
+
+#[derive(Component)] struct VolumeReadout;
+
+fn spawn_volume_readout(mut commands: Commands) {
+    Hud::spawn(&mut commands, Vec2::new(-1.0, -1.0), 0, Color::srgb(0.7, 0.7, 0.7), "")
+        .insert(VolumeReadout);
+}
+
+fn update_volume_readout(
+    volume: Res<MasterVolume>,
+    mut q_readout: Query<&mut Text, With<VolumeReadout>>,
+) {
+    if !volume.is_changed() {return}
+    let Ok(mut text) = q_readout.get_single_mut() else {return};
+    text.sections[0].value = format!("volume {:+} dB", volume.db);
+}
+
+#[derive(Component)] struct ClipIndicator;
+
+fn spawn_clip_indicator(mut commands: Commands) {
+    Hud::spawn(&mut commands, Vec2::new(1.0, -1.0), 0, Color::srgb(1.0, 0.2, 0.2), "LIMIT")
+        .insert((ClipIndicator, Visibility::Hidden));
+}
+
+/** Flashes the clip indicator while the limiter reduces the volume by more than 3 dB. */
+fn update_clip_indicator(
+    backend: Res<LoopTunesBackend>,
+    time: Res<Time>,
+    mut hold: Local<f32>,
+    mut q_indicator: Query<&mut Visibility, With<ClipIndicator>>,
+) {
+    let Ok(mut visibility) = q_indicator.get_single_mut() else {return};
+    if backend.take_limiter_reduction() < 0.7 {
+        *hold = 0.25;
+    }
+    *hold -= time.delta_seconds();
+    let wanted = if *hold > 0.0 {Visibility::Inherited} else {Visibility::Hidden};
+    if *visibility != wanted {
+        *visibility = wanted;
     }
 }
 
+enum Output {
+    Device{commands: Sender<Command>, name: String, opened: Instant},
+    /** Renders the scene at the rate at which a device would have played it, discarding the samples. */
+    Null{source: Box<LoopSource>, start: Instant, pulled: u64},
+}
+
+/** Messages from the ECS to the audio thread. */
+enum Command {
+    /** The scene and the playback position at which it should start, or None to start it right away. */
+    Scene(Arc<Scene>, Option<u64>),
+    Tap(Option<Sender<Vec<f32>>>),
+    Volume(f32),
+    Pause(bool),
+    /** Moves the playback position and the point it returns to when nothing plays, in seconds. */
+    Seek(f64),
+}
+
+/** Everything a new source needs to continue where the previous one left off. */
+#[derive(Clone)]
+struct Mixing {
+    scene: Arc<Scene>,
+    tap: Option<Sender<Vec<f32>>>,
+    volume: f32,
+    fade: f32,
+    paused: bool,
+    /** Time at which playback starts when a tree is activated while nothing plays, in seconds. */
+    start: f64,
+    /** Number of seeks so far. */
+    seeks: u64,
+}
+
 #[derive(Resource)]
 pub struct LoopTunesBackend {
-    producer: Sender<f32>,
-    position: u32,
+    output: Output,
+    sample_rate: u32,
+    frame_size: usize,
+    /** Playback position that is being heard, in samples. It never wraps, such that cycles stay in sync indefinitely. */
+    clock: Arc<AtomicU64>,
+    /** Lowest gain applied by the limiter, as f32 bits. */
+    meter: Arc<AtomicU32>,
+    /** Number of seeks that the clock reflects. */
+    applied_seeks: Arc<AtomicU64>,
+    mixing: Mixing,
+    /** Boundary of a quantized launch, in seconds. Scenes published before it start at the boundary. */
+    launch: Option<f64>,
 }
 struct LoopTunesInternal {
     #[allow(unused)] stream: OutputStream,
-    #[allow(unused)] sink: Sink,
+    sink: Sink,
 }
 
 impl LoopTunesBackend {
-    const SAMPLE_RATE: u32 = 48000;
-    const FRAME_SIZE: usize = 2048;
-    const BUFFER: usize = 2048*4;
+    pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+    /** Below about 63Hz the DC blocker becomes unstable, the limits keep well clear of that. */
+    pub const SAMPLE_RATE_RANGE: std::ops::RangeInclusive<u32> = 8000..=384000;
+    pub const FRAME_SIZE: usize = 2048;
 
-    pub fn reset(&mut self) {
-        self.position = Self::SAMPLE_RATE * 128;
+    /** Parses the value of --rate. */
+    pub fn parse_sample_rate(text: &str) -> Result<u32, String> {
+        let range = Self::SAMPLE_RATE_RANGE;
+        text.parse().ok().filter(|rate| range.contains(rate))
+            .ok_or(format!("Invalid sample rate: {}, must be between {} and {} Hz", text, range.start(), range.end()))
     }
 
-    pub fn send_buffer(&mut self, samples: &Vec<f32>) {
-        for &sample in samples.iter() {
-            _ = self.producer.send(sample);
+    fn new(sample_rate: u32, frame_size: usize, fade: f32) -> Self {
+        let clock = Arc::new(AtomicU64::new(0));
+        let meter = Arc::new(AtomicU32::new(1.0f32.to_bits()));
+        let applied_seeks = Arc::new(AtomicU64::new(0));
+        let mixing = Mixing{
+            scene: default(),
+            tap: None,
+            volume: 1.0,
+            fade,
+            paused: false,
+            start: 0.0,
+            seeks: 0,
+        };
+        let (_, source) = LoopSource::new(mixing.clone(), clock.clone(), meter.clone(), applied_seeks.clone(), sample_rate, frame_size);
+        LoopTunesBackend{
+            output: Output::Null{source: Box::new(source), start: Instant::now(), pulled: 0},
+            sample_rate,
+            frame_size,
+            clock,
+            meter,
+            applied_seeks,
+            mixing,
+            launch: None,
         }
-    
-        // Update playback position
-        self.position += samples.len() as u32;
-        self.position %= Self::SAMPLE_RATE * 256 * 3;
     }
 
-    pub fn has_free_space(&self) -> bool {
-        Self::BUFFER - self.producer.len() >= Self::FRAME_SIZE
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
     }
 
-    pub fn time_chunk(&self) -> Vec<f64> {
-        (0..Self::FRAME_SIZE as u32).map(|i| (self.position + i) as f64 / Self::SAMPLE_RATE as f64).collect()
+    /** Changes the sample rate, keeping the playback position at the same time. */
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        let position = self.clock.load(Ordering::Relaxed);
+        self.clock.store(position * sample_rate as u64 / self.sample_rate as u64, Ordering::Relaxed);
+        if sample_rate != self.sample_rate {
+            // Recordings can't change their sample rate.
+            self.stop_tap();
+        }
+        self.sample_rate = sample_rate;
     }
 
-    pub fn elapsed_seconds(&self) -> f32 {
-        (self.position as f32 - (2 * Self::BUFFER) as f32) / Self::SAMPLE_RATE as f32
+    /** Creates a source that continues playing the current scene from the current position. */
+    fn new_source(&self) -> (Sender<Command>, LoopSource) {
+        LoopSource::new(self.mixing.clone(), self.clock.clone(), self.meter.clone(), self.applied_seeks.clone(), self.sample_rate, self.frame_size)
+    }
+
+    fn send(&mut self, command: Command) {
+        match &mut self.output {
+            Output::Device{commands, ..} => _ = commands.send(command),
+            Output::Null{source, ..} => source.apply(command),
+        }
+    }
+
+    /** Replaces the scene that is being played. The audio thread fades from the old to the new scene. */
+    pub fn publish(&mut self, scene: Scene) {
+        self.launch = self.launch.filter(|&at| at > self.elapsed_seconds());
+        let start = self.launch.map(|at| (at * self.sample_rate as f64).round() as u64);
+        self.mixing.scene = Arc::new(scene);
+        self.send(Command::Scene(self.mixing.scene.clone(), start));
+    }
+
+    /** Lets the scenes that are published until the given time start exactly at that time, in seconds. */
+    pub fn launch_at(&mut self, seconds: f64) {
+        self.launch = Some(seconds);
+    }
+
+    /** How long before a launch its scene must be published, in seconds. The audio thread renders a frame ahead. */
+    pub fn lead_seconds(&self) -> f64 {
+        2.0 * self.frame_size as f64 / self.sample_rate as f64 + 0.1
+    }
+
+    /** Sets the factor by which the mix is scaled. */
+    pub fn set_volume(&mut self, volume: f32) {
+        self.mixing.volume = volume;
+        self.send(Command::Volume(volume));
+    }
+
+    /** Sends a copy of every buffer that is played to the returned receiver, until stop_tap is called. */
+    pub fn start_tap(&mut self) -> Receiver<Vec<f32>> {
+        let (tx, rx) = unbounded();
+        self.mixing.tap = Some(tx.clone());
+        self.send(Command::Tap(Some(tx)));
+        rx
+    }
+
+    pub fn stop_tap(&mut self) {
+        self.mixing.tap = None;
+        self.send(Command::Tap(None));
+    }
+
+    pub fn is_tapped(&self) -> bool {
+        self.mixing.tap.is_some()
+    }
+
+    /** The scene that is being played. */
+    pub fn scene(&self) -> &Scene {
+        &self.mixing.scene
+    }
+
+    /** Pausing keeps the playback position, while the output is silent. */
+    pub fn set_paused(&mut self, paused: bool) {
+        self.mixing.paused = paused;
+        self.send(Command::Pause(paused));
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mixing.paused
+    }
+
+    /** Continues playback from the given time, in seconds. When nothing plays, playback returns to this time. */
+    pub fn seek(&mut self, seconds: f64) {
+        let seconds = seconds.max(0.0);
+        self.mixing.start = seconds;
+        self.launch = None;
+        self.mixing.seeks += 1;
+        self.send(Command::Seek(seconds));
+    }
+
+    /** Number of seeks so far, such that times that were based on the old playback position can be updated. */
+    pub fn seek_count(&self) -> u64 {
+        self.mixing.seeks
+    }
+
+    /** Time of the last seek, in seconds. */
+    pub fn seek_position(&self) -> f64 {
+        self.mixing.start
+    }
+
+    /**
+     * Times of the samples starting at the given playback position.
+     * The sample index is exact, so the only rounding is the division, which stays below a nanosecond for years of playback.
+     */
+    pub fn time_chunk_at(position: u64, length: usize, sample_rate: u32) -> Vec<f64> {
+        (0..length as u64).map(|i| (position + i) as f64 / sample_rate as f64).collect()
+    }
+
+    /** Returns the lowest gain applied by the limiter since the previous call. */
+    pub fn take_limiter_reduction(&self) -> f32 {
+        f32::from_bits(self.meter.swap(1.0f32.to_bits(), Ordering::Relaxed))
+    }
+
+    /** Time of the sample that is being heard. */
+    pub fn elapsed_seconds(&self) -> f64 {
+        // Until the audio thread has applied the last seek, its clock still shows the old position.
+        if self.applied_seeks.load(Ordering::Acquire) < self.mixing.seeks {
+            return self.mixing.start
+        }
+        self.clock.load(Ordering::Relaxed) as f64 / self.sample_rate as f64
     }
 }
 
+/** Lets the null output render the samples a device would have requested by now. */
+fn drive_null_output(mut backend: ResMut<LoopTunesBackend>) {
+    let sample_rate = backend.sample_rate as f64;
+    // Pulling samples is not a change that systems watching the backend need to see.
+    let Output::Null{source, start, pulled} = &mut backend.bypass_change_detection().output else {return};
+    let wall_clock = (start.elapsed().as_secs_f64() * sample_rate) as u64;
+    while *pulled < wall_clock {
+        for _ in 0..Scene::CHANNELS {
+            source.next();
+        }
+        *pulled += 1;
+    }
+}
+
+/**
+ * Synthesizes the published scene on the audio thread, one frame at a time.
+ * This way, playback does not depend on the frame rate of the game.
+ * When a new scene is published, the old scene is faded out while the new one fades in.
+ * Nodes that did not change add up to the same signal, while all other nodes get a click-free ramp.
+ */
 pub struct LoopSource {
-    consumer: Receiver<f32>,
-    last: f32, // Add bit of ease when stopped.
+    commands: Receiver<Command>,
+    mixing: Mixing,
+    /** The scene that is being faded out, and the number of samples per channel since the fade started. */
+    previous: Option<(Arc<Scene>, usize)>,
+    /** Scene that was published during a fade, which is faded in afterwards. */
+    pending: Option<Arc<Scene>>,
+    /** Scene that waits for a launch boundary, and the playback position at which it starts. */
+    scheduled: Option<(Arc<Scene>, u64)>,
+    /** One arena for each root that is mixed in parallel. */
+    arenas: Vec<Arena>,
+    pool: TaskPool,
+    bus: MasterBus,
+    clock: Arc<AtomicU64>,
+    meter: Arc<AtomicU32>,
+    applied_seeks: Arc<AtomicU64>,
+    position: u64,
+    sample_rate: u32,
+    frame_size: usize,
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl LoopSource {
+    fn new(mixing: Mixing, clock: Arc<AtomicU64>, meter: Arc<AtomicU32>, applied_seeks: Arc<AtomicU64>, sample_rate: u32, frame_size: usize) -> (Sender<Command>, Self) {
+        let (tx, rx) = unbounded();
+        let position = clock.load(Ordering::Relaxed);
+        applied_seeks.store(mixing.seeks, Ordering::Release);
+        (tx, LoopSource{
+            commands: rx,
+            mixing,
+            previous: None,
+            pending: None,
+            scheduled: None,
+            arenas: default(),
+            pool: Scene::mixer_pool(),
+            bus: MasterBus::new(sample_rate),
+            clock,
+            meter,
+            applied_seeks,
+            position,
+            sample_rate,
+            frame_size,
+            buffer: Vec::with_capacity(frame_size * Scene::CHANNELS),
+            index: 0,
+        })
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::Scene(scene, Some(start)) if start > self.position => self.scheduled = Some((scene, start)),
+            Command::Scene(scene, _) => {
+                self.scheduled = None;
+                self.show(scene);
+            },
+            Command::Tap(tap) => self.mixing.tap = tap,
+            Command::Volume(volume) => self.mixing.volume = volume,
+            Command::Pause(paused) => self.mixing.paused = paused,
+            Command::Seek(seconds) => {
+                self.mixing.start = seconds;
+                self.mixing.seeks += 1;
+                self.position = self.start_position();
+                // The boundary of a scheduled scene no longer makes sense.
+                if let Some((scene, _)) = self.scheduled.take() {
+                    self.show(scene);
+                }
+            },
+        }
+    }
+
+    /** Fades to the scene, or queues it if a fade is in progress. */
+    fn show(&mut self, scene: Arc<Scene>) {
+        if self.previous.is_some() {
+            self.pending = Some(scene);
+        } else {
+            self.start_fade(scene);
+        }
+    }
+
+    fn start_fade(&mut self, scene: Arc<Scene>) {
+        let previous = std::mem::replace(&mut self.mixing.scene, scene);
+        if self.fade_samples() > 0 {
+            self.previous = Some((previous, 0));
+        }
+    }
+
+    fn start_position(&self) -> u64 {
+        (self.mixing.start * self.sample_rate as f64).round() as u64
+    }
+
+    /** Length of a fade, in samples per channel. */
+    fn fade_samples(&self) -> usize {
+        (self.mixing.fade * self.sample_rate as f32) as usize
+    }
+
+    /** Mixes the current scene, fading from the previous scene if there is one. */
+    fn mix(&mut self, time: &[f64]) -> Vec<f32> {
+        let mut buffer = self.mixing.scene.mix_parallel(time, self.sample_rate, &self.pool, &mut self.arenas);
+        let fade = self.fade_samples();
+        let Some((previous, faded_samples)) = &mut self.previous else {return buffer};
+        let old = previous.mix_parallel(time, self.sample_rate, &self.pool, &mut self.arenas);
+        for (sample, (new, old)) in buffer.chunks_exact_mut(Scene::CHANNELS).zip(old.chunks_exact(Scene::CHANNELS)).enumerate() {
+            let amount = f32::min(1.0, (*faded_samples + sample) as f32 / fade as f32);
+            for (new, old) in new.iter_mut().zip(old) {
+                *new = *old + (*new - *old) * amount;
+            }
+        }
+        self.arenas[0].give(old);
+        *faded_samples += time.len();
+        if *faded_samples >= fade {
+            self.previous = None;
+            if let Some(scene) = self.pending.take() {
+                self.start_fade(scene);
+            }
+        }
+        buffer
+    }
+
+    /** Mixes a frame, switching to the scheduled scene at the exact sample where it starts. */
+    fn mix_frame(&mut self, time: &[f64]) -> Vec<f32> {
+        let split = match &self.scheduled {
+            Some((_, start)) if *start < self.position + time.len() as u64 => (*start - self.position) as usize,
+            _ => return self.mix(time),
+        };
+        let mut buffer = self.mix(&time[..split]);
+        if let Some((scene, _)) = self.scheduled.take() {
+            self.show(scene);
+        }
+        let rest = self.mix(&time[split..]);
+        buffer.extend_from_slice(&rest);
+        self.arenas[0].give(rest);
+        buffer
+    }
+
+    fn render_frame(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            self.apply(command);
+        }
+
+        // A scheduled scene starts once its position is reached, or right away if nothing is playing.
+        let silent = self.mixing.scene.roots.is_empty() && self.previous.is_none();
+        if self.scheduled.as_ref().is_some_and(|(_, start)| *start <= self.position || silent) {
+            let (scene, _) = self.scheduled.take().unwrap();
+            self.show(scene);
+        }
+
+        // If nothing is playing, return to the start, unless playback is paused.
+        let silent = self.mixing.scene.roots.is_empty() && self.previous.is_none();
+        let playing = !silent && !self.mixing.paused;
+        if silent && !self.mixing.paused {
+            self.position = self.start_position();
+        }
+        if !playing {
+            self.buffer.clear();
+            self.buffer.resize(self.frame_size * Scene::CHANNELS, 0.0);
+        } else {
+            let time = LoopTunesBackend::time_chunk_at(self.position, self.frame_size, self.sample_rate);
+            let buffer = self.mix_frame(&time);
+            let old = std::mem::replace(&mut self.buffer, buffer);
+            self.arenas[0].give(old);
+            let volume = self.mixing.volume;
+            self.buffer.iter_mut().for_each(|sample| *sample *= volume);
+        }
+        self.bus.process(&mut self.buffer, self.mixing.scene.roots.len());
+        // For non-negative floats, the order of the bits matches the order of the values.
+        self.meter.fetch_min(self.bus.take_reduction().to_bits(), Ordering::Relaxed);
+        // Frames are rendered about one frame before they are played.
+        let heard = if playing {self.position.saturating_sub(self.frame_size as u64)} else {self.position};
+        self.clock.store(heard, Ordering::Relaxed);
+        self.applied_seeks.store(self.mixing.seeks, Ordering::Release);
+        self.index = 0;
+
+        // Recordings also receive the silence, to keep them running in real time.
+        if let Some(tap) = &self.mixing.tap {
+            _ = tap.send(self.buffer.clone());
+        }
+
+        // Update playback position
+        if playing {
+            self.position += self.frame_size as u64;
+        }
+    }
 }
 
 impl Iterator for LoopSource {
@@ -90,12 +718,12 @@ impl Iterator for LoopSource {
 
     #[inline]
     fn next(&mut self) -> Option<f32> {
-        if let Ok(value) = self.consumer.try_recv() {
-            self.last = value;
-            return Some(value);
+        if self.index >= self.buffer.len() {
+            self.render_frame();
         }
-        self.last *= 0.99;
-        return Some(self.last);
+        let value = self.buffer[self.index];
+        self.index += 1;
+        Some(value)
     }
 }
 
@@ -107,12 +735,12 @@ impl Source for LoopSource {
 
     #[inline]
     fn channels(&self) -> u16 {
-        1
+        Scene::CHANNELS as u16
     }
 
     #[inline]
     fn sample_rate(&self) -> u32 {
-        LoopTunesBackend::SAMPLE_RATE
+        self.sample_rate
     }
 
     #[inline]
@@ -125,9 +753,3 @@ impl Source for LoopSource {
         Ok(())
     }
 }
-
-impl Drop for LoopSource {
-    fn drop(&mut self) {
-        println!("LoopTunes Backend source died. RIP.")
-    }
-}
@@ -18,16 +18,39 @@ use smallvec::SmallVec;
 
 // Modules
 mod archiving; use archiving::*;
+#[cfg(not(target_family="wasm"))] mod benchmark;
 mod clipboard; use clipboard::*;
 mod connector; use connector::*;
 mod cyclewave; use cyclewave::*;
+mod frequency_entry; use frequency_entry::*;
+mod hud; use hud::*;
+mod launch; use launch::*;
 mod looptunes; use looptunes::*; 
 mod micetrack; use micetrack::*;
+mod mixer; use mixer::*;
 mod pancamera; use pancamera::*;
+mod period; use period::*;
+mod recording; use recording::*;
+#[cfg(not(target_family="wasm"))] mod render;
 mod starttune; use starttune::*;
+mod transport; use transport::*;
+mod tuning; use tuning::*;
 mod utilities; use utilities::*;
+#[cfg(not(target_family="wasm"))] mod wavfile;
 
 fn main() {
+    // Headless modes
+    #[cfg(not(target_family="wasm"))]
+    if render::run_from_args() {return}
+    #[cfg(not(target_family="wasm"))]
+    if benchmark::run_from_args() {return}
+    if command_line_flag("--list-devices") {
+        for name in output_device_names() {
+            println!("{}", name);
+        }
+        return
+    }
+
     App::new()
         .insert_resource(ClearColor(Color::srgb(0.0, 0.0, 0.0)))
         .add_plugins((
@@ -48,9 +71,21 @@ fn main() {
             ArchivingPlugin,
             ConnectorPlugin,
             CycleWavePlugin,
-            LoopTunesPlugin,
+            FrequencyEntryPlugin,
+            HudPlugin,
+            LaunchPlugin,
+            LoopTunesPlugin{
+                null_audio: command_line_flag("--no-audio"),
+                device: command_line_value("--device"),
+                sample_rate: command_line_value("--rate").and_then(|rate| LoopTunesBackend::parse_sample_rate(&rate).map_err(|err| println!("{}", err)).ok()).unwrap_or(LoopTunesBackend::DEFAULT_SAMPLE_RATE),
+                latency: command_line_value("--latency").and_then(|latency| Latency::parse(&latency)).unwrap_or(Latency::Normal),
+                fade: command_line_value("--fade").and_then(|ms| ms.parse::<f32>().ok()).filter(|&ms| ms >= 0.0).unwrap_or(20.0) / 1000.0,
+            },
             MiceTrackPlugin,
             PanCameraPlugin(MouseButton::Right),
+            RecordingPlugin,
+            TransportPlugin,
+            TuningPlugin,
         ))
         .add_systems(Startup, setup)
         .add_systems(Startup, spawn_cyclewaves)
@@ -58,12 +93,12 @@ fn main() {
         .add_systems(Update, (
             hover_cycle, 
             connect_create,
-            (delete_circle, clone_circle, drag_cycle, draw_cycle, connect_cycle, scroll_cycle.run_if(|keyboard:Res<ButtonInput<KeyCode>>|is_shift(&keyboard))),
+            (delete_circle, clone_circle, drag_cycle, draw_cycle, connect_cycle, scroll_cycle.run_if(|keyboard:Res<ButtonInput<KeyCode>>|is_shift(&keyboard) && !is_ctrl(&keyboard) && !is_alt(&keyboard)), scroll_gain.run_if(|keyboard:Res<ButtonInput<KeyCode>>|is_alt(&keyboard) && !is_shift(&keyboard) && !is_ctrl(&keyboard)), scroll_cents.run_if(|keyboard:Res<ButtonInput<KeyCode>>|is_ctrl(&keyboard) && !is_shift(&keyboard) && !is_alt(&keyboard)), transpose_tree.run_if(|keyboard:Res<ButtonInput<KeyCode>>|is_ctrl(&keyboard) && is_shift(&keyboard) && !is_alt(&keyboard))),
             connect_drop
-        ).chain())
-        .add_systems(Update, (colorize, add_circle))
-        .configure_sets(Update, (ZoomSystem).run_if(|keyboard:Res<ButtonInput<KeyCode>>|!is_shift(&keyboard)))
-        .add_systems(PostUpdate, play_everything)
+        ).chain().run_if(not(is_scrubbing)))
+        .add_systems(Update, (colorize, change_interpolation, change_modulation, change_relative, change_pan, add_circle.run_if(not(is_entering_frequency))))
+        .configure_sets(Update, (ZoomSystem).run_if(|keyboard:Res<ButtonInput<KeyCode>>, segment_hover:Res<SegmentHover>|!is_shift(&keyboard) && !is_alt(&keyboard) && !is_ctrl(&keyboard) && segment_hover.0.is_none()))
+        .add_systems(PostUpdate, publish_scene)
         .add_systems(SpawnScene, track_hover)
         .run();
 }
@@ -482,6 +517,68 @@ fn scroll_cycle(
     }
 }
 
+fn scroll_gain(
+    mut q_cycles: Query<&mut Cycle>,
+    hover: Res<Hover>,
+    mut scroll: EventReader<MouseWheel>,
+) {
+    let Some(entity) = hover.entity else {return};
+    let Ok(mut cycle) = q_cycles.get_mut(entity) else {return};
+    for event in scroll.read() {
+        cycle.change_gain(soft_signum(event.y));
+    }
+}
+
+fn scroll_cents(
+    mut q_cycles: Query<&mut Cycle>,
+    hover: Res<Hover>,
+    mut scroll: EventReader<MouseWheel>,
+) {
+    let Some(entity) = hover.entity else {return};
+    let Ok(mut cycle) = q_cycles.get_mut(entity) else {return};
+    for event in scroll.read() {
+        // Same direction as scroll_cycle.
+        cycle.change_cents(-(soft_signum(event.y) + 10.0 * soft_signum(event.x)));
+    }
+}
+
+/**
+ * Transposes the notes of the whole tree under the mouse.
+ * Cycles that follow a transposed parent or root move along with it, the others are transposed themselves.
+ */
+fn transpose_tree(
+    mut q_cycles: Query<&mut Cycle>,
+    q_parents: Query<&Parent, With<Cycle>>,
+    q_children: Query<&ChildCycles>,
+    hover: Res<Hover>,
+    mut scroll: EventReader<MouseWheel>,
+) {
+    let Some(entity) = hover.entity else {return};
+    let root = q_parents.iter_ancestors(entity).last().unwrap_or(entity);
+    for event in scroll.read() {
+        let steps = -(soft_signum(event.y) + 12.0 * soft_signum(event.x)) as i32;
+        // Stack of nodes with whether their nearest note above them and their root moved.
+        let mut stack = vec![(root, false, false)];
+        while let Some((node, parent_moved, root_moved)) = stack.pop() {
+            let Ok(mut cycle) = q_cycles.get_mut(node) else {continue};
+            let follows = match cycle.relative {
+                Relative::Absolute => false,
+                Relative::Parent(_) => parent_moved,
+                Relative::Root(_) => root_moved,
+            };
+            let moved = follows || cycle.is_note();
+            if !follows && moved {
+                cycle.transpose(steps);
+            }
+            if let Ok(children) = q_children.get(node) {
+                let root_moved = if node == root {moved} else {root_moved};
+                let parent_moved = if cycle.is_note() {moved} else {parent_moved};
+                stack.extend(children.0.iter().map(|&child| (child, parent_moved, root_moved)));
+            }
+        }
+    }
+}
+
 #[derive(Component)]
 struct Highlight;
 
@@ -521,84 +618,51 @@ fn child_cycles(
     }
 }
 
-#[inline]
-fn synthesize<'a>(cycle: &'a Cycle, wave: &'a Wave, time: impl Iterator<Item = &'a f64> + 'a, phase: f64) -> impl Iterator<Item = f32> + 'a {
-    time.map(move |&t| {
-        let wave_pos = t * cycle.frequency() - phase;
-        let index = (wave_pos.fract() * 1024.0) as usize;
-        wave.pattern[index]
-    })
-}
-
-fn play_everything(
-    q_cycles: Query<(&Cycle,&Wave,Option<&ChildCycles>), With<Playing>>,
-    q_roots: Query<Entity, (Without<Parent>, With<Playing>)>,
-    mut backend: ResMut<LoopTunesBackend>,
-) {
-    // Only produce when there is space in the buffer.
-    if !backend.has_free_space() {return}
-    
-    // If nothing is playing reset playback.
-    if q_roots.is_empty() {
-        backend.reset();
-        return
-    }
-
-    // Prepare a stack of nodes.
-    struct Node {
-        entity: Entity,
-        volume: Vec<f32>,
-    }
-    let mut stack: Vec<Node> = Vec::with_capacity(32);
-    for entity in q_roots.iter() {
-        let volume = [0.2;1024].into();
-        stack.push(Node{entity, volume});
-    }
-
-    // Collect the samples from each node
-    let time: Vec<f64> = backend.time_chunk();
-    let mut result: Vec<f32> = [0.0;1024].into();
-    while let Some(node) = stack.pop() {
-        let Ok((cycle, wave, option_children)) = q_cycles.get(node.entity) else {continue};
+/** Copies the playing cycle trees into a scene that can be mixed. */
+fn collect_scene(
+    q_cycles: &Query<(&Cycle,&Wave,Option<&ChildCycles>), With<Playing>>,
+    roots: impl Iterator<Item = Entity>,
+) -> Scene {
+    let mut scene = Scene::default();
+    let mut stack: Vec<(Entity, Option<usize>)> = roots.map(|entity| (entity, None)).collect();
+    while let Some((entity, parent)) = stack.pop() {
+        let Ok((cycle, wave, option_children)) = q_cycles.get(entity) else {continue};
+        let index = scene.push(parent, SceneNode::new(cycle, wave.pattern, wave.band_limited.clone(), option_children.is_none()));
         if let Some(children) = option_children {
-            // Recurse into child nodes
             for &child in children.0.iter() {
-                // Get the child cycle, assuming it is being played.
-                let Ok((child_cycle, _, _)) = q_cycles.get(child) else {continue};
-
-                // Mix this node!
-                let child_volume 
-                    = synthesize(&cycle, &wave, time.iter(), child_cycle.phase_in_parent() as f64)
-                    .zip(node.volume.iter())
-                    .map(|(s,v)| s*v);
-
-                stack.push(Node { 
-                    entity: child,
-                    volume: child_volume.collect(),
-                });
+                stack.push((child, Some(index)));
             }
-        } else {
-            // Play this node!
-            let samples 
-                = synthesize(&cycle, &wave, time.iter(), 0.0)
-                .zip(node.volume.iter())
-                .map(|(s,v)| (s - wave.average)*v);
-
-            result
-                .iter_mut()
-                .zip(samples)
-                .for_each(|(r, s)| *r += s);
         }
     }
+    scene
+}
+
+/** Cycles whose change affects the sound of the scene. */
+type SceneChanged = (With<Cycle>, Or<(Changed<Cycle>, Changed<Wave>, Changed<Playing>, Changed<ChildCycles>, Changed<Parent>)>);
 
-    backend.send_buffer(&result);
+/** Sends a new snapshot of the playing trees to the audio thread whenever they change. */
+fn publish_scene(
+    q_cycles: Query<(&Cycle,&Wave,Option<&ChildCycles>), With<Playing>>,
+    q_roots: Query<Entity, (Without<Parent>, With<Playing>)>,
+    q_changed: Query<(), SceneChanged>,
+    mut removed_playing: RemovedComponents<Playing>,
+    mut removed_children: RemovedComponents<ChildCycles>,
+    mut removed_parent: RemovedComponents<Parent>,
+    mut backend: ResMut<LoopTunesBackend>,
+) {
+    let removed = removed_playing.read().count() + removed_children.read().count() + removed_parent.read().count();
+    if q_changed.is_empty() && removed == 0 {return}
+    backend.publish(collect_scene(&q_cycles, q_roots.iter()));
 }
 
 fn spawn_cyclewaves(
-    commands: Commands,
+    mut commands: Commands,
     mouse: Res<MousePos>,
 ) {
-    paste_tree(In(START_TUNE.into()), commands, mouse);
+    match decode_tree(START_TUNE) {
+        Ok(tree) => spawn_tree(&mut commands, mouse.position, &tree),
+        Err(err) => println!("Failed to load start tune: {}", err),
+    }
 }
 
 fn colorize(
@@ -613,10 +677,92 @@ fn colorize(
     cycle.color = Color::hsv(360.0 * hue, 1.0, 1.0).into();
 }
 
+/** Switches the hovered circle between nearest, linear and cubic interpolation. */
+fn change_interpolation(
+    hover: Res<Hover>,
+    mut q_cycles: Query<&mut Cycle>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyI) {return}
+    let Some(ent) = hover.entity else {return};
+    let Ok(mut cycle) = q_cycles.get_mut(ent) else {return};
+    cycle.interpolation = cycle.interpolation.next();
+}
+
+/** Switches how the parent of the hovered circle affects it, between amplitude, frequency, phase and ring modulation. */
+fn change_modulation(
+    hover: Res<Hover>,
+    mut q_cycles: Query<&mut Cycle>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyM) {return}
+    let Some(ent) = hover.entity else {return};
+    let Ok(mut cycle) = q_cycles.get_mut(ent) else {return};
+    cycle.modulation = cycle.modulation.next();
+}
+
+/**
+ * Switches the hovered note between an absolute frequency and one that follows its parent or root.
+ * The parent is the nearest note above it, the root can only be followed if it is a note.
+ * The offset is chosen such that the frequency stays the same.
+ */
+fn change_relative(
+    hover: Res<Hover>,
+    mut q_cycles: Query<&mut Cycle>,
+    q_parents: Query<&Parent, With<Cycle>>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyT) {return}
+    let Some(ent) = hover.entity else {return};
+    let note = |entity| q_cycles.get(entity).ok().and_then(Cycle::note);
+    let parent_note = q_parents.iter_ancestors(ent).find_map(note);
+    let root_note = q_parents.iter_ancestors(ent).last().and_then(note);
+    let Ok(mut cycle) = q_cycles.get_mut(ent) else {return};
+    let Some(frequency) = cycle.note() else {
+        println!("Only notes can follow another note");
+        return
+    };
+    cycle.relative = match (cycle.relative, parent_note, root_note) {
+        (Relative::Absolute, Some(parent), _) => Relative::Parent(frequency - parent),
+        (Relative::Absolute | Relative::Parent(_), _, Some(root)) => Relative::Root(frequency - root),
+        (Relative::Absolute, None, None) => {
+            println!("There is no note above this circle to follow");
+            Relative::Absolute
+        },
+        _ => Relative::Absolute,
+    };
+}
+
+/**
+ * While X is held, the hovered circle is panned towards the side of the circle the mouse is on.
+ * Shift + X toggles whether the parent's pattern drives the pan.
+ */
+fn change_pan(
+    hover: Res<Hover>,
+    mut q_cycles: Query<&mut Cycle>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if !keyboard.pressed(KeyCode::KeyX) {return}
+    let Some(ent) = hover.entity else {return};
+    let Ok(mut cycle) = q_cycles.get_mut(ent) else {return};
+    if is_shift(&keyboard) {
+        if keyboard.just_pressed(KeyCode::KeyX) {
+            cycle.auto_pan = !cycle.auto_pan;
+        }
+        return
+    }
+    // Snap to the center, so panning can be undone.
+    let pan = (hover.position.x.clamp(-1.0, 1.0) * 20.0).round() / 20.0;
+    if cycle.pan != pan {
+        cycle.pan = pan;
+    }
+}
+
 fn add_circle(
     mut commands: Commands,
     mouse: Res<MousePos>,
     keyboard: Res<ButtonInput<KeyCode>>,
+    active_tuning: Res<ActiveTuning>,
 ) {
     let function = match () {
         _ if keyboard.just_pressed(KeyCode::Backquote) => |_: f32| 1.0,
@@ -643,6 +789,7 @@ fn add_circle(
         cycle: Cycle {
             color: Color::hsv(thread_rng().gen_range(0.0..360.0), 1.0, 1.0).into(),
             frequency: frequency,
+            tuning: active_tuning.0.clone(),
             ..Default::default()
         },
         wave: Wave::new(function),
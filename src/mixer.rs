@@ -0,0 +1,696 @@
+use std::collections::VecDeque;
+use std::f64::consts::TAU;
+use std::sync::Arc;
+
+use bevy::tasks::{TaskPool, TaskPoolBuilder};
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use crate::{Cycle, Wave};
+
+/**
+ * Plain-data copy of the playing cycle trees.
+ * Can be mixed without access to the ECS, which allows rendering songs offline.
+ */
+#[derive(Default)]
+pub struct Scene {
+    pub nodes: Vec<SceneNode>,
+    pub roots: Vec<usize>,
+}
+
+/** Selects how mix_stems splits the scene. */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stems {
+    Roots,
+    Leaves,
+}
+
+/**
+ * Copies of a wave pattern with fewer and fewer harmonics.
+ * Level k contains the harmonics up to 512 >> k, such that high notes can be played without aliasing.
+ */
+#[derive(Default)]
+pub struct BandLimited(Vec<[f32; Wave::LENGTH]>);
+
+impl BandLimited {
+    const LEVELS: usize = 10;
+
+    pub fn new(pattern: &[f32; Wave::LENGTH]) -> Self {
+        let mut re: Vec<f64> = pattern.iter().map(|&v| v as f64).collect();
+        let mut im = vec![0.0; Wave::LENGTH];
+        fft(&mut re, &mut im, false);
+
+        let levels = (1..Self::LEVELS).map(|level| {
+            // Remove the harmonics above the limit, together with their mirror images.
+            let limit = (Wave::LENGTH / 2) >> level;
+            let (mut level_re, mut level_im) = (re.clone(), im.clone());
+            for bin in limit + 1 .. Wave::LENGTH - limit {
+                level_re[bin] = 0.0;
+                level_im[bin] = 0.0;
+            }
+            fft(&mut level_re, &mut level_im, true);
+            let mut table = [0.0; Wave::LENGTH];
+            for (t, v) in table.iter_mut().zip(level_re) {
+                *t = (v / Wave::LENGTH as f64) as f32;
+            }
+            table
+        });
+        BandLimited(levels.collect())
+    }
+
+    /** Selects the table with the most harmonics that stay below the Nyquist frequency. */
+    pub fn table<'a>(&'a self, pattern: &'a [f32; Wave::LENGTH], frequency: f64, sample_rate: u32) -> &'a [f32; Wave::LENGTH] {
+        let nyquist = sample_rate as f64 / 2.0;
+        let level = (0..Self::LEVELS).find(|level| ((Wave::LENGTH / 2) >> level) as f64 * frequency <= nyquist).unwrap_or(Self::LEVELS - 1);
+        match level {
+            0 => pattern,
+            // Waves that have not been analyzed yet are played as is.
+            _ => self.0.get(level - 1).unwrap_or(pattern),
+        }
+    }
+}
+
+/** In-place radix-2 fast fourier transform. The inverse transform is not scaled. */
+fn fft(re: &mut [f64], im: &mut [f64], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse {1.0} else {-1.0};
+    let mut length = 2;
+    while length <= n {
+        let angle = sign * TAU / length as f64;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let a = start + k;
+                let b = a + length / 2;
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        length *= 2;
+    }
+}
+
+/** How a wave pattern is read between its samples. */
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Interpolation {
+    /** Uses the nearest sample below, which gives the crunchy chip sound. */
+    #[default] Nearest,
+    /** Reads the band-limited tables, like Cubic. */
+    Linear,
+    /** Catmull-Rom spline through the surrounding samples of the band-limited tables. */
+    Cubic,
+}
+
+impl Interpolation {
+    pub fn next(self) -> Self {
+        match self {
+            Interpolation::Nearest => Interpolation::Linear,
+            Interpolation::Linear => Interpolation::Cubic,
+            Interpolation::Cubic => Interpolation::Nearest,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Interpolation::Nearest => "nearest",
+            Interpolation::Linear => "linear",
+            Interpolation::Cubic => "cubic",
+        }
+    }
+
+    /** Reads the pattern at the given position, where 1.0 is one full cycle. */
+    #[inline]
+    pub fn sample(self, pattern: &[f32; Wave::LENGTH], position: f64) -> f32 {
+        const MASK: usize = Wave::LENGTH - 1;
+        let x = (position - position.floor()) * Wave::LENGTH as f64;
+        let index = x as usize;
+        let t = (x - index as f64) as f32;
+        let at = |offset: usize| pattern[(index + offset) & MASK];
+        match self {
+            Interpolation::Nearest => at(0),
+            Interpolation::Linear => at(0) + (at(1) - at(0)) * t,
+            Interpolation::Cubic => {
+                let (p0, p1, p2, p3) = (at(MASK), at(0), at(1), at(2));
+                let a = -0.5*p0 + 1.5*p1 - 1.5*p2 + 0.5*p3;
+                let b = p0 - 2.5*p1 + 2.0*p2 - 0.5*p3;
+                let c = 0.5*(p2 - p0);
+                ((a*t + b)*t + c)*t + p1
+            },
+        }
+    }
+}
+
+/** How the pattern of a parent affects its child. */
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Modulation {
+    /** Scales the volume of the child, from silent at 0 to full volume at 1. */
+    #[default] Amplitude,
+    /** Bends the frequency of the child up and down. Follows the pattern of the parent at the parent's own frequency. */
+    Frequency,
+    /** Shifts the phase of the child back and forth. */
+    Phase,
+    /** Multiplies the child by the pattern centered around zero, which adds sum and difference frequencies instead of the child itself. */
+    Ring,
+}
+
+impl Modulation {
+    /** Largest change in frequency, relative to the frequency of the child, which is about a semitone. */
+    pub const FREQUENCY_DEPTH: f64 = 0.06;
+    /** Largest shift in phase, in cycles. */
+    pub const PHASE_DEPTH: f64 = 0.25;
+
+    pub fn next(self) -> Self {
+        match self {
+            Modulation::Amplitude => Modulation::Frequency,
+            Modulation::Frequency => Modulation::Phase,
+            Modulation::Phase => Modulation::Ring,
+            Modulation::Ring => Modulation::Amplitude,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Modulation::Amplitude => "amplitude",
+            Modulation::Frequency => "FM",
+            Modulation::Phase => "PM",
+            Modulation::Ring => "ring",
+        }
+    }
+}
+
+/**
+ * Integral of the pattern centered around zero, at LENGTH + 1 points, where the pattern spans 1.0.
+ * Lets frequency modulation compute the phase of a child at any time, without keeping state between frames.
+ */
+struct Integral([f64; Wave::LENGTH + 1]);
+
+impl Integral {
+    fn new(pattern: &[f32; Wave::LENGTH]) -> Self {
+        let mut integral = [0.0; Wave::LENGTH + 1];
+        for (i, &s) in pattern.iter().enumerate() {
+            integral[i + 1] = integral[i] + (2.0 * s as f64 - 1.0) / Wave::LENGTH as f64;
+        }
+        Integral(integral)
+    }
+
+    /** Integral from 0 to the given position, for the pattern as read with nearest interpolation. */
+    fn at(&self, position: f64) -> f64 {
+        let cycles = position.floor();
+        let x = (position - cycles) * Wave::LENGTH as f64;
+        let index = usize::min(x as usize, Wave::LENGTH - 1);
+        let slope = self.0[index + 1] - self.0[index];
+        cycles * self.0[Wave::LENGTH] + self.0[index] + (x - index as f64) * slope
+    }
+}
+
+pub struct SceneNode {
+    pub frequency: f64,
+    pub phase: f64,
+    pub pattern: [f32; Wave::LENGTH],
+    pub interpolation: Interpolation,
+    pub band_limited: Arc<BandLimited>,
+    /** Volume factor for this node and its children. */
+    pub gain: f32,
+    /** From -1 (left) to 1 (right). Added to the pan of the parent. */
+    pub pan: f32,
+    /** Let the parent's pattern drive the pan instead of the volume. */
+    pub auto_pan: bool,
+    /** How the parent's pattern affects this node. */
+    pub modulation: Modulation,
+    /** The parent's pattern is scaled by depth and then offset is added, before it modulates this node. */
+    pub depth: f32,
+    pub offset: f32,
+    /** Subtracted from the samples of a leaf. Only used by the legacy DC mode, otherwise the master bus removes DC. */
+    pub average: f32,
+    pub leaf: bool,
+    pub children: SmallVec<[usize; 8]>,
+}
+
+impl SceneNode {
+    /** Copies the settings of the cycle, children are added by Scene::push. */
+    pub fn new(cycle: &Cycle, pattern: [f32; Wave::LENGTH], band_limited: Arc<BandLimited>, leaf: bool) -> Self {
+        SceneNode{
+            frequency: cycle.frequency(),
+            phase: cycle.phase_in_parent() as f64,
+            pattern,
+            interpolation: cycle.interpolation,
+            band_limited,
+            gain: cycle.gain(),
+            pan: cycle.pan,
+            auto_pan: cycle.auto_pan,
+            modulation: cycle.modulation,
+            depth: cycle.depth,
+            offset: cycle.offset,
+            average: if cycle.legacy_dc {pattern.iter().sum::<f32>() / Wave::LENGTH as f32} else {0.0},
+            leaf,
+            children: SmallVec::new(),
+        }
+    }
+}
+
+impl Scene {
+    pub const ROOT_VOLUME: f32 = 0.2;
+    pub const CHANNELS: usize = 2;
+
+    /** Adds a node to the scene, either as root or as child of the given parent. */
+    pub fn push(&mut self, parent: Option<usize>, node: SceneNode) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(index),
+            None => self.roots.push(index),
+        }
+        index
+    }
+
+    /**
+     * Worker threads for mix_parallel.
+     * This is deliberately not the ComputeTaskPool: the game's systems run on that pool, so the audio thread would wait
+     * behind them and drop out whenever a frame is slow. The render command also mixes without an App, where that pool does not exist.
+     */
+    pub fn mixer_pool() -> TaskPool {
+        TaskPoolBuilder::new().thread_name("Mixer".into()).build()
+    }
+
+    /**
+     * Mixes all leaf nodes of the scene for the given sample times into interleaved stereo samples.
+     * Each root is mixed on its own and the roots are then added in order, such that mix_parallel gives the exact same result.
+     */
+    pub fn mix(&self, time: &[f64], sample_rate: u32, arena: &mut Arena) -> Vec<f32> {
+        let mut result = arena.take(time.len() * Self::CHANNELS);
+        for &root in self.roots.iter() {
+            let samples = self.mix_root(root, time, sample_rate, arena);
+            add_samples(&mut result, &samples);
+            arena.give(samples);
+        }
+        result
+    }
+
+    /** Same as mix, but mixes the roots in parallel on the given pool, using an arena per root. */
+    pub fn mix_parallel(&self, time: &[f64], sample_rate: u32, pool: &TaskPool, arenas: &mut Vec<Arena>) -> Vec<f32> {
+        arenas.resize_with(usize::max(arenas.len(), self.roots.len().max(1)), Arena::default);
+        if self.roots.len() < 2 {
+            return self.mix(time, sample_rate, &mut arenas[0]);
+        }
+
+        let root_samples = pool.scope(|scope| {
+            for (&root, arena) in self.roots.iter().zip(arenas.iter_mut()) {
+                scope.spawn(async move { self.mix_root(root, time, sample_rate, arena) });
+            }
+        });
+
+        let mut result = arenas[0].take(time.len() * Self::CHANNELS);
+        for (samples, arena) in root_samples.into_iter().zip(arenas.iter_mut()) {
+            add_samples(&mut result, &samples);
+            arena.give(samples);
+        }
+        result
+    }
+
+    fn mix_root(&self, root: usize, time: &[f64], sample_rate: u32, arena: &mut Arena) -> Vec<f32> {
+        let mut result = arena.take(time.len() * Self::CHANNELS);
+        self.mix_leaves(&[root], time, sample_rate, arena, |_, _, samples| add_samples(&mut result, samples));
+        result
+    }
+
+    /** Node indices of the roots or leaves, which each get their own stem. */
+    pub fn stem_nodes(&self, stems: Stems) -> Vec<usize> {
+        match stems {
+            Stems::Roots => self.roots.clone(),
+            Stems::Leaves => (0..self.nodes.len()).filter(|&i| self.nodes[i].leaf).collect(),
+        }
+    }
+
+    /**
+     * Mixes the scene into separate buffers, one for each node returned by stem_nodes.
+     * The stems add up to the result of mix.
+     */
+    pub fn mix_stems(&self, time: &[f64], sample_rate: u32, stems: Stems, arena: &mut Arena) -> Vec<Vec<f32>> {
+        let stem_nodes = self.stem_nodes(stems);
+        let mut stem_of = vec![0; self.nodes.len()];
+        for (stem, &node) in stem_nodes.iter().enumerate() {
+            stem_of[node] = stem;
+        }
+
+        let mut result: Vec<Vec<f32>> = stem_nodes.iter().map(|_| arena.take(time.len() * Self::CHANNELS)).collect();
+        self.mix_leaves(&self.roots, time, sample_rate, arena, |root, leaf, samples| {
+            let stem = match stems {
+                Stems::Roots => stem_of[root],
+                Stems::Leaves => stem_of[leaf],
+            };
+            add_samples(&mut result[stem], samples);
+        });
+        result
+    }
+
+    /**
+     * Synthesizes each leaf node below the given roots and passes the stereo samples to output, together with the root and leaf node index.
+     * All buffers come from the arena, and children with the same phase share the evaluation of their parent's pattern.
+     */
+    fn mix_leaves(&self, roots: &[usize], time: &[f64], sample_rate: u32, arena: &mut Arena, mut output: impl FnMut(usize, usize, &[f32])) {
+        // Prepare a stack of nodes.
+        struct Node {
+            root: usize,
+            index: usize,
+            volume: Vec<f32>,
+            pan: Vec<f32>,
+            /** Added to the position in the pattern, for frequency and phase modulation. */
+            warp: Option<Vec<f64>>,
+        }
+        let length = time.len();
+        let mut stack: Vec<Node> = Vec::with_capacity(32);
+        for &index in roots.iter() {
+            let mut volume = arena.take(length);
+            volume.fill(Self::ROOT_VOLUME * self.nodes[index].gain);
+            let mut pan = arena.take(length);
+            pan.fill(self.nodes[index].pan);
+            stack.push(Node{root: index, index, volume, pan, warp: None});
+        }
+
+        // Collect the samples from each node
+        let mut patterns: SmallVec<[(f64, Vec<f32>); 4]> = SmallVec::new();
+        while let Some(node) = stack.pop() {
+            let scene_node = &self.nodes[node.index];
+            if !scene_node.leaf {
+                // Recurse into child nodes
+                for &child in scene_node.children.iter() {
+                    let child_node = &self.nodes[child];
+                    let pattern = match patterns.iter().position(|(phase, _)| *phase == child_node.phase) {
+                        Some(found) => &patterns[found].1,
+                        None => {
+                            let mut pattern = arena.take(length);
+                            synthesize(scene_node, time, sample_rate, child_node.phase, node.warp.as_deref(), &mut pattern);
+                            patterns.push((child_node.phase, pattern));
+                            &patterns.last().unwrap().1
+                        },
+                    };
+
+                    // Mix this node!
+                    let mut volume = arena.take(length);
+                    let mut pan = arena.take(length);
+                    let iter = volume.iter_mut().zip(pan.iter_mut()).zip(pattern.iter().zip(node.volume.iter().zip(node.pan.iter())));
+                    let (depth, offset) = (child_node.depth, child_node.offset);
+                    if child_node.auto_pan {
+                        for ((volume, pan), (s, (v, p))) in iter {
+                            *volume = v * child_node.gain;
+                            *pan = p + child_node.pan + depth * (2.0*s - 1.0);
+                        }
+                    } else {
+                        for ((volume, pan), (s, (v, p))) in iter {
+                            let level = match child_node.modulation {
+                                Modulation::Amplitude => offset + depth * s,
+                                Modulation::Ring => offset + depth * (2.0 * s - 1.0),
+                                Modulation::Frequency | Modulation::Phase => 1.0,
+                            };
+                            *volume = level * v * child_node.gain;
+                            *pan = p + child_node.pan;
+                        }
+                    }
+
+                    // Frequency modulation integrates the parent's pattern, phase modulation uses it directly.
+                    let warp = match child_node.modulation {
+                        Modulation::Frequency if scene_node.frequency > 0.0 => {
+                            let integral = Integral::new(&scene_node.pattern);
+                            let deviation = child_node.frequency * Modulation::FREQUENCY_DEPTH;
+                            let (depth, offset) = (depth as f64 * deviation / scene_node.frequency, offset as f64 * deviation);
+                            let mut warp = arena.take_phases(length);
+                            for (w, t) in warp.iter_mut().zip(time) {
+                                *w = depth * integral.at(t * scene_node.frequency - child_node.phase) + offset * t;
+                            }
+                            Some(warp)
+                        },
+                        Modulation::Phase => {
+                            let mut warp = arena.take_phases(length);
+                            for (w, s) in warp.iter_mut().zip(pattern) {
+                                *w = Modulation::PHASE_DEPTH * (offset + depth * (2.0 * s - 1.0)) as f64;
+                            }
+                            Some(warp)
+                        },
+                        _ => None,
+                    };
+
+                    stack.push(Node {
+                        root: node.root,
+                        index: child,
+                        volume,
+                        pan,
+                        warp,
+                    });
+                }
+                for (_, pattern) in patterns.drain(..) {
+                    arena.give(pattern);
+                }
+            } else {
+                // Play this node! Centered nodes play at full volume on both channels.
+                let mut samples = arena.take(length * Self::CHANNELS);
+                let mut mono = arena.take(length);
+                synthesize(scene_node, time, sample_rate, 0.0, node.warp.as_deref(), &mut mono);
+                for (((s, v), p), frame) in mono.iter().zip(node.volume.iter()).zip(node.pan.iter()).zip(samples.chunks_exact_mut(Self::CHANNELS)) {
+                    let s = (s - scene_node.average)*v;
+                    let p = p.clamp(-1.0, 1.0);
+                    frame[0] = s * f32::min(1.0, 1.0 - p);
+                    frame[1] = s * f32::min(1.0, 1.0 + p);
+                }
+                output(node.root, node.index, &samples);
+                arena.give(samples);
+                arena.give(mono);
+            }
+            arena.give(node.volume);
+            arena.give(node.pan);
+            if let Some(warp) = node.warp {
+                arena.give_phases(warp);
+            }
+        }
+    }
+}
+
+fn add_samples(result: &mut [f32], samples: &[f32]) {
+    result
+        .iter_mut()
+        .zip(samples)
+        .for_each(|(r, s)| *r += s);
+}
+
+/** Sample buffers that are reused between mixes, such that mixing does not allocate once warmed up. */
+#[derive(Default)]
+pub struct Arena {
+    buffers: Vec<Vec<f32>>,
+    phases: Vec<Vec<f64>>,
+}
+
+impl Arena {
+    /** Returns a zeroed buffer of the given length. */
+    pub fn take(&mut self, length: usize) -> Vec<f32> {
+        let mut buffer = self.buffers.pop().unwrap_or_default();
+        buffer.clear();
+        buffer.resize(length, 0.0);
+        buffer
+    }
+
+    /** Returns a buffer to the arena. */
+    pub fn give(&mut self, buffer: Vec<f32>) {
+        self.buffers.push(buffer);
+    }
+
+    /** Returns a zeroed buffer for positions in a pattern, which need double precision. */
+    fn take_phases(&mut self, length: usize) -> Vec<f64> {
+        let mut buffer = self.phases.pop().unwrap_or_default();
+        buffer.clear();
+        buffer.resize(length, 0.0);
+        buffer
+    }
+
+    fn give_phases(&mut self, buffer: Vec<f64>) {
+        self.phases.push(buffer);
+    }
+}
+
+/** One-pole high-pass filter that removes the DC offset of each channel. */
+pub struct DcBlocker {
+    coefficient: f32,
+    input: [f32; Scene::CHANNELS],
+    output: [f32; Scene::CHANNELS],
+}
+
+impl DcBlocker {
+    const CUTOFF: f32 = 10.0;
+
+    pub fn new(sample_rate: u32) -> Self {
+        DcBlocker{
+            coefficient: 1.0 - std::f32::consts::TAU * Self::CUTOFF / sample_rate as f32,
+            input: [0.0; Scene::CHANNELS],
+            output: [0.0; Scene::CHANNELS],
+        }
+    }
+
+    /** Processes interleaved stereo samples in place. */
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(Scene::CHANNELS) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let output = *sample - self.input[channel] + self.coefficient * self.output[channel];
+                self.input[channel] = *sample;
+                self.output[channel] = output;
+                *sample = output;
+            }
+        }
+    }
+}
+
+/**
+ * Final stage between the mix and the output.
+ * Removes DC, compensates for the number of playing roots and limits the peaks with a short look-ahead,
+ * such that large scenes don't clip.
+ */
+pub struct MasterBus {
+    dc_blocker: DcBlocker,
+    /** Frames waiting to be output, together with the gain they need to stay below the ceiling. */
+    delay: VecDeque<([f32; Scene::CHANNELS], f32)>,
+    lookahead: usize,
+    gain: f32,
+    release: f32,
+    compensation: f32,
+    smoothing: f32,
+    /** Lowest gain applied since the last call to take_reduction. */
+    reduction: f32,
+}
+
+impl MasterBus {
+    const CEILING: f32 = 0.98;
+    const LOOKAHEAD: f32 = 0.0015;
+    const RELEASE: f32 = 0.15;
+    const SMOOTHING: f32 = 0.05;
+
+    pub fn new(sample_rate: u32) -> Self {
+        let lookahead = (Self::LOOKAHEAD * sample_rate as f32).ceil() as usize;
+        MasterBus{
+            dc_blocker: DcBlocker::new(sample_rate),
+            delay: std::iter::repeat_n(([0.0; Scene::CHANNELS], 1.0), lookahead).collect(),
+            lookahead,
+            gain: 1.0,
+            release: 1.0 / (Self::RELEASE * sample_rate as f32),
+            compensation: 1.0,
+            smoothing: 1.0 / (Self::SMOOTHING * sample_rate as f32),
+            reduction: 1.0,
+        }
+    }
+
+    /** Number of frames by which the output is delayed. */
+    pub fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    /** Volume factor for the given number of roots. Uncorrelated roots add up by their power. */
+    pub fn compensation(roots: usize) -> f32 {
+        1.0 / f32::sqrt(roots.max(1) as f32)
+    }
+
+    /** Skips smoothing the compensation towards the given number of roots, for when it is known up front. */
+    pub fn settle(&mut self, roots: usize) {
+        self.compensation = Self::compensation(roots);
+    }
+
+    /** Processes interleaved stereo samples in place. */
+    pub fn process(&mut self, samples: &mut [f32], roots: usize) {
+        // The offset of the leaves would otherwise eat the headroom of the limiter.
+        self.dc_blocker.process(samples);
+
+        let target = Self::compensation(roots);
+        for frame in samples.chunks_exact_mut(Scene::CHANNELS) {
+            self.compensation += (target - self.compensation) * self.smoothing;
+            let input = [frame[0] * self.compensation, frame[1] * self.compensation];
+            let peak = f32::max(input[0].abs(), input[1].abs());
+            let required = if peak > Self::CEILING {Self::CEILING / peak} else {1.0};
+            self.delay.push_back((input, required));
+
+            // Ramp the gain down over the delay line, such that it is low enough when a peak leaves it, and slowly release it afterwards.
+            let (output, required) = self.delay.pop_front().unwrap();
+            let mut gain = f32::min(required, self.gain + (1.0 - self.gain) * self.release);
+            for (distance, &(_, required)) in self.delay.iter().enumerate() {
+                gain = gain.min(self.gain + (required - self.gain) / (distance + 2) as f32);
+            }
+            self.gain = gain;
+            self.reduction = self.reduction.min(self.gain);
+            frame[0] = output[0] * self.gain;
+            frame[1] = output[1] * self.gain;
+        }
+    }
+
+    /** Returns the lowest gain applied by the limiter since the previous call. */
+    pub fn take_reduction(&mut self) -> f32 {
+        std::mem::replace(&mut self.reduction, 1.0)
+    }
+}
+
+/** Writes the samples of the node at the given times to output. The warp, if any, is added to the position in the pattern. */
+#[inline]
+fn synthesize(node: &SceneNode, time: &[f64], sample_rate: u32, phase: f64, warp: Option<&[f64]>, output: &mut [f32]) {
+    let table = match node.interpolation {
+        Interpolation::Nearest => &node.pattern,
+        _ => node.band_limited.table(&node.pattern, node.frequency, sample_rate),
+    };
+    match warp {
+        None => for (o, &t) in output.iter_mut().zip(time) {
+            let wave_pos = t * node.frequency - phase;
+            *o = node.interpolation.sample(table, wave_pos);
+        },
+        Some(warp) => for ((o, &t), &w) in output.iter_mut().zip(time).zip(warp) {
+            let wave_pos = t * node.frequency - phase + w;
+            *o = node.interpolation.sample(table, wave_pos);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::default;
+
+    fn node(hz: f64, generator: fn(f32) -> f32, modulation: Modulation, leaf: bool) -> SceneNode {
+        let pattern = Wave::new(generator).pattern;
+        let cycle = Cycle{hz: Some(hz), modulation, interpolation: Interpolation::Cubic, ..default()};
+        SceneNode::new(&cycle, pattern, Arc::new(BandLimited::new(&pattern)), leaf)
+    }
+
+    #[test]
+    fn parallel_mix_is_bit_identical() {
+        // Roots of different shapes, such that they finish at different times and add up to different values.
+        let mut scene = Scene::default();
+        scene.push(None, node(220.0, Wave::SINE, Modulation::Amplitude, true));
+        for (root, modulation) in [Modulation::Amplitude, Modulation::Frequency, Modulation::Phase, Modulation::Ring].into_iter().enumerate() {
+            let root = scene.push(None, node(0.5 * (root + 1) as f64, Wave::TRIANGLE, Modulation::Amplitude, false));
+            for leaf in 0..=root {
+                scene.push(Some(root), node(110.0 * (leaf + 2) as f64 / 3.0, Wave::SAWTOOTH, modulation, true));
+            }
+        }
+
+        let sample_rate = 48000;
+        let pool = Scene::mixer_pool();
+        let mut arena = Arena::default();
+        let mut arenas = Vec::new();
+        for position in [0, 12345, 1 << 30] {
+            let time = crate::LoopTunesBackend::time_chunk_at(position, 512, sample_rate);
+            let expected = scene.mix(&time, sample_rate, &mut arena);
+            let actual = scene.mix_parallel(&time, sample_rate, &pool, &mut arenas);
+            assert!(expected.iter().any(|&sample| sample != 0.0));
+            assert_eq!(expected.iter().map(|sample| sample.to_bits()).collect::<Vec<_>>(), actual.iter().map(|sample| sample.to_bits()).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,160 @@
+/**
+ * Exact repeat period of a set of cycles, stored as a fraction of seconds.
+ * Phase offsets only shift cycles within this period, so they do not affect it.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LoopPeriod {
+    numerator: u64,
+    denominator: u64,
+    /** False if some of the cycles have no rational period and were ignored. */
+    pub exact: bool,
+}
+
+impl LoopPeriod {
+    const MAX_DENOMINATOR: u64 = 1024;
+    const MAX_SECONDS: u64 = 24 * 60 * 60;
+
+    /**
+     * Computes the least common multiple of the periods of the given frequencies.
+     * Returns None if none of the frequencies has a rational period, or if the period is unreasonably long.
+     */
+    pub fn of(frequencies: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let mut result: Option<Self> = None;
+        let mut exact = true;
+        for frequency in frequencies {
+            // A frequency of p/q Hz has a period of q/p seconds.
+            let Some((p, q)) = as_fraction(frequency) else {
+                exact = false;
+                continue
+            };
+            result = Some(match result {
+                None => Self{numerator: q, denominator: p, exact},
+                Some(r) => {
+                    // lcm(a/b, c/d) = lcm(a,c) / gcd(b,d), for reduced fractions.
+                    let numerator = lcm(r.numerator, q)?;
+                    let denominator = gcd(r.denominator, p);
+                    if numerator / denominator > Self::MAX_SECONDS {return None}
+                    Self{numerator, denominator, exact}
+                }
+            });
+        }
+        result.map(|r| Self{exact, ..r})
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.numerator as f64 / self.denominator as f64
+    }
+
+    /** Length of the period in samples, rounded to the nearest sample. */
+    pub fn samples(&self, sample_rate: u32) -> u64 {
+        (self.numerator * sample_rate as u64 + self.denominator / 2) / self.denominator
+    }
+
+    /** Formats the period in the same style as the frequency names. */
+    pub fn name(&self) -> String {
+        let seconds = self.seconds();
+        let text = if seconds >= 60.0 {
+            format!("{}m{}s", (seconds / 60.0).floor(), trim_decimals(seconds % 60.0))
+        } else if seconds >= 1.0 {
+            format!("{}s", trim_decimals(seconds))
+        } else {
+            format!("{}ms", trim_decimals(seconds * 1000.0))
+        };
+        if self.exact {text} else {format!("~{}", text)}
+    }
+}
+
+fn trim_decimals(value: f64) -> String {
+    let text = format!("{:.3}", value);
+    text.trim_end_matches('0').trim_end_matches('.').into()
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn lcm(a: u64, b: u64) -> Option<u64> {
+    (a / gcd(a, b)).checked_mul(b)
+}
+
+/** Finds a reduced fraction p/q equal to the value, using continued fractions with a limited denominator. */
+fn as_fraction(value: f64) -> Option<(u64, u64)> {
+    if value.is_nan() || value <= 0.0 {return None}
+    let (mut h0, mut h1) = (0u64, 1u64);
+    let (mut k0, mut k1) = (1u64, 0u64);
+    let mut x = value;
+    loop {
+        let a = x.floor();
+        if a > u32::MAX as f64 {return None}
+        let a = a as u64;
+        let (h2, k2) = (a * h1 + h0, a * k1 + k0);
+        if k2 > LoopPeriod::MAX_DENOMINATOR {return None}
+        if (h2 as f64 / k2 as f64 - value).abs() <= value * 1e-12 {return Some((h2, k2))}
+        (h0, h1) = (h1, h2);
+        (k0, k1) = (k1, k2);
+        x = 1.0 / (x - a as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** Frequency of the MIDI key in 12-TET with A4 at 440Hz. */
+    fn equal_tempered(key: i32) -> f64 {
+        440.0 * f64::powf(2.0, (key - 69) as f64 / 12.0)
+    }
+
+    #[test]
+    fn exact_chord_repeats_at_the_common_period() {
+        // A just major chord over 200Hz, on top of a rhythm of 1.5Hz.
+        let period = LoopPeriod::of([200.0, 250.0, 300.0, 1.5]).unwrap();
+        assert_eq!((period.numerator, period.denominator, period.exact), (2, 1, true));
+        assert_eq!(period.name(), "2s");
+        assert_eq!(period.samples(48000), 96000);
+
+        let period = LoopPeriod::of([200.0, 250.0, 300.0]).unwrap();
+        assert_eq!(period.name(), "20ms");
+    }
+
+    #[test]
+    fn equal_tempered_chord_is_inexact() {
+        // Only A4 has a rational frequency in an A minor chord.
+        let period = LoopPeriod::of([equal_tempered(69), equal_tempered(72), equal_tempered(76)]).unwrap();
+        assert!(!period.exact);
+        assert_eq!((period.numerator, period.denominator), (1, 440));
+        assert_eq!(period.name(), "~2.273ms");
+        // Without A4, nothing has a period.
+        assert_eq!(LoopPeriod::of([equal_tempered(72), equal_tempered(76)]), None);
+    }
+
+    #[test]
+    fn single_frequency() {
+        let period = LoopPeriod::of([0.125]).unwrap();
+        assert_eq!((period.numerator, period.denominator, period.exact), (8, 1, true));
+        assert_eq!(period.seconds(), 8.0);
+        assert_eq!(period.name(), "8s");
+        assert_eq!(LoopPeriod::of([1.0 / 96.0]).unwrap().name(), "1m36s");
+        assert_eq!(LoopPeriod::of([440.0]).unwrap().samples(44100), 100);
+    }
+
+    #[test]
+    fn no_period() {
+        assert_eq!(LoopPeriod::of([]), None);
+        assert_eq!(LoopPeriod::of([0.0, -1.0, f64::NAN]), None);
+        // Periods of 1023 and 1024 seconds only line up after more than a day.
+        assert_eq!(LoopPeriod::of([1.0 / 1023.0, 1.0 / 1024.0]), None);
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(as_fraction(0.5), Some((1, 2)));
+        assert_eq!(as_fraction(1.0 / 3.0), Some((1, 3)));
+        assert_eq!(as_fraction(441.5), Some((883, 2)));
+        assert_eq!(as_fraction(std::f64::consts::PI), None);
+        assert_eq!(as_fraction(0.0), None);
+    }
+}
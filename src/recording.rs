@@ -0,0 +1,167 @@
+#[cfg(target_family="wasm")] pub use self::wasm::*;
+#[cfg(target_family="wasm")] mod wasm {
+    use bevy::prelude::*;
+
+    /** Recording to disk is not available in the browser. */
+    pub struct RecordingPlugin;
+    impl Plugin for RecordingPlugin {
+        fn build(&self, _app: &mut App) {}
+    }
+}
+
+#[cfg(not(target_family="wasm"))] pub use self::native::*;
+#[cfg(not(target_family="wasm"))] mod native {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use bevy::app::AppExit;
+    use bevy::prelude::*;
+    use crossbeam_channel::RecvTimeoutError;
+
+    use crate::wavfile::WavWriter;
+    use crate::{println, Hud, LoopTunesBackend, Scene};
+
+    pub struct RecordingPlugin;
+    impl Plugin for RecordingPlugin {
+        fn build(&self, app: &mut App) {
+            app
+                .insert_resource(Recorder::default())
+                .add_systems(Startup, spawn_indicator)
+                .add_systems(Update, (toggle_recording, update_indicator).chain())
+                .add_systems(Last, finish_on_exit);
+        }
+    }
+
+    /** Counts the frames written by the recording thread. Only present while recording. */
+    #[derive(Resource, Default)]
+    struct Recorder {
+        frames: Option<Arc<AtomicU64>>,
+        sample_rate: u32,
+        /** The thread writing the last recording, which may still be finishing its file. */
+        thread: Option<JoinHandle<()>>,
+        /** Tells the thread to finish once no more audio arrives, for when the audio stops before the tap is closed. */
+        stop: Arc<AtomicBool>,
+    }
+
+    #[derive(Component)] struct RecordingIndicator;
+
+    fn spawn_indicator(mut commands: Commands) {
+        Hud::spawn(&mut commands, Vec2::new(-1.0, 1.0), 0, Color::srgb(1.0, 0.2, 0.2), "")
+            .insert((RecordingIndicator, Visibility::Hidden));
+    }
+
+    fn toggle_recording(
+        mut recorder: ResMut<Recorder>,
+        mut backend: ResMut<LoopTunesBackend>,
+        keyboard: Res<ButtonInput<KeyCode>>,
+    ) {
+        // The backend stops the tap when the sample rate changes.
+        if recorder.frames.is_some() && !backend.is_tapped() {
+            recorder.frames = None;
+        }
+
+        if !keyboard.just_pressed(KeyCode::KeyR) {return}
+        if recorder.frames.is_some() {
+            // Closing the tap makes the recording thread finish the file.
+            backend.stop_tap();
+            recorder.frames = None;
+            return
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let mut path = format!("loop-tunes-{}.wav", timestamp);
+        let sample_rate = backend.sample_rate();
+        let mut writer = match WavWriter::create(&path, sample_rate, Scene::CHANNELS as u16) {
+            Ok(ok) => ok,
+            Err(err) => { println!("Failed to start recording: {:?}", err); return }
+        };
+        println!("Recording to {}", path);
+
+        let frames = Arc::new(AtomicU64::new(0));
+        let thread_frames = frames.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let buffers = backend.start_tap();
+        let thread = thread::spawn(move || {
+            let mut part = 1;
+            loop {
+                let buffer = match buffers.recv_timeout(Duration::from_millis(100)) {
+                    Ok(buffer) => buffer,
+                    Err(RecvTimeoutError::Timeout) if !thread_stop.load(Ordering::Relaxed) => continue,
+                    Err(_) => break,
+                };
+                // Continue long recordings in a new file when the current one is full.
+                if !writer.has_room(buffer.len()) {
+                    part += 1;
+                    let next_path = format!("loop-tunes-{}-{}.wav", timestamp, part);
+                    let next = match WavWriter::create(&next_path, sample_rate, Scene::CHANNELS as u16) {
+                        Ok(ok) => ok,
+                        Err(err) => { println!("Failed to continue recording: {:?}", err); break }
+                    };
+                    match std::mem::replace(&mut writer, next).finish() {
+                        Ok(()) => println!("Saved recording to {}, continuing in {}", path, next_path),
+                        Err(err) => println!("Failed to write recording: {:?}", err),
+                    }
+                    path = next_path;
+                }
+                if let Err(err) = writer.write(&buffer) {
+                    println!("Failed to write recording: {:?}", err);
+                    break
+                }
+                thread_frames.fetch_add((buffer.len() / Scene::CHANNELS) as u64, Ordering::Relaxed);
+            }
+            match writer.finish() {
+                Ok(()) => println!("Saved recording to {}", path),
+                Err(err) => println!("Failed to write recording: {:?}", err),
+            }
+        });
+        recorder.frames = Some(frames);
+        recorder.sample_rate = sample_rate;
+        recorder.thread = Some(thread);
+        recorder.stop = stop;
+    }
+
+    /** Waits for the recording thread to finish its file, as exiting would otherwise leave it without a valid header. */
+    fn finish_on_exit(
+        mut recorder: ResMut<Recorder>,
+        mut backend: ResMut<LoopTunesBackend>,
+        mut exit: EventReader<AppExit>,
+    ) {
+        if exit.is_empty() {return}
+        exit.clear();
+        let Some(thread) = recorder.thread.take() else {return};
+        if backend.is_tapped() {
+            backend.stop_tap();
+        }
+        recorder.stop.store(true, Ordering::Relaxed);
+        recorder.frames = None;
+        if thread.join().is_err() {
+            println!("Failed to finish recording");
+        }
+    }
+
+    fn update_indicator(
+        recorder: Res<Recorder>,
+        mut q_indicator: Query<(&mut Text, &mut Visibility), With<RecordingIndicator>>,
+    ) {
+        let Ok((mut text, mut visibility)) = q_indicator.get_single_mut() else {return};
+        match &recorder.frames {
+            Some(frames) => {
+                let seconds = frames.load(Ordering::Relaxed) / recorder.sample_rate as u64;
+                let label = format!("REC {}:{:02}", seconds / 60, seconds % 60);
+                if text.sections[0].value != label {
+                    text.sections[0].value = label;
+                }
+                if *visibility != Visibility::Inherited {
+                    *visibility = Visibility::Inherited;
+                }
+            },
+            None if *visibility != Visibility::Hidden => {
+                *visibility = Visibility::Hidden;
+            },
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use crate::wavfile::WavWriter;
+use crate::{decode_tree, println, Arena, DcBlocker, LoopPeriod, LoopTunesBackend, MasterBus, Scene, Stems};
+
+const USAGE: &str = "usage: loop-tunes render <share-string-or-file>... [--seconds N | --loop] [--stems roots|leaves] [--rate HZ] [-o out.wav]";
+
+/**
+ * Runs the headless render mode if it was requested on the command line.
+ * Returns false if the game should be started instead.
+ */
+pub fn run_from_args() -> bool {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) != Some("render") {return false}
+    if let Err(err) = render(&args[1..]) {
+        println!("{}", err);
+        exit(1);
+    }
+    true
+}
+
+struct RenderOptions {
+    inputs: Vec<String>,
+    seconds: f64,
+    seamless_loop: bool,
+    stems: Option<Stems>,
+    sample_rate: u32,
+    output: String,
+}
+
+fn parse_options(args: &[String]) -> Result<RenderOptions, String> {
+    let mut inputs = Vec::new();
+    let mut seconds = 10.0;
+    let mut seamless_loop = false;
+    let mut stems = None;
+    let mut sample_rate = LoopTunesBackend::DEFAULT_SAMPLE_RATE;
+    let mut output = String::from("out.wav");
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--seconds" => {
+                let value = iter.next().ok_or(USAGE)?;
+                seconds = value.parse().map_err(|_| format!("Invalid number of seconds: {}", value))?;
+            },
+            "--loop" => {
+                seamless_loop = true;
+            },
+            "--stems" => {
+                stems = Some(match iter.next().map(String::as_str) {
+                    Some("roots") => Stems::Roots,
+                    Some("leaves") => Stems::Leaves,
+                    _ => return Err(USAGE.into()),
+                });
+            },
+            "--rate" => {
+                let value = iter.next().ok_or(USAGE)?;
+                sample_rate = LoopTunesBackend::parse_sample_rate(value)?;
+            },
+            "-o" | "--output" => {
+                output = iter.next().ok_or(USAGE)?.clone();
+            },
+            _ if !arg.starts_with('-') => {
+                inputs.push(arg.clone());
+            },
+            _ => return Err(USAGE.into()),
+        }
+    }
+    if inputs.is_empty() {return Err(USAGE.into())}
+    Ok(RenderOptions{
+        inputs,
+        seconds,
+        seamless_loop,
+        stems,
+        sample_rate,
+        output,
+    })
+}
+
+/** Reads the share string from a file, or uses the argument itself if no such file exists. */
+fn load_share_string(input: &str) -> Result<String, String> {
+    if Path::new(input).is_file() {
+        read_to_string(input)
+            .map(|text| text.trim().into())
+            .map_err(|err| format!("Failed to read {}: {:?}", input, err))
+    } else {
+        Ok(input.trim().into())
+    }
+}
+
+/** Inserts the stem name before the extension of the output path, e.g. song-leaf3.wav. */
+fn stem_path(output: &str, name: &str) -> PathBuf {
+    let path = Path::new(output);
+    let stem = path.file_stem().map_or("out".into(), |s| s.to_string_lossy());
+    let extension = path.extension().map_or("wav".into(), |s| s.to_string_lossy());
+    path.with_file_name(format!("{}-{}.{}", stem, name, extension))
+}
+
+/**
+ * Mixes the given number of samples of the scene, starting at start, and passes them to write with the index of the stem.
+ * The mix passes through the master bus, which is warmed up on the samples before start.
+ * Stems get the same compensation for the number of roots and have their DC removed after the same warm-up, but skip the limiter,
+ * such that they add up to the mix wherever it is not limited.
+ */
+fn write_scene(scene: &Scene, start: u64, length: u64, sample_rate: u32, stems: Option<Stems>, mut write: impl FnMut(usize, &[f32]) -> std::io::Result<()>) -> std::io::Result<()> {
+    let mut arenas = vec![Arena::default()];
+    let pool = Scene::mixer_pool();
+    let mut bus = MasterBus::new(sample_rate);
+    bus.settle(scene.roots.len());
+    let compensation = MasterBus::compensation(scene.roots.len());
+    let stem_count = stems.map_or(0, |stems| scene.stem_nodes(stems).len());
+    let mut dc_blockers: Vec<DcBlocker> = (0..stem_count).map(|_| DcBlocker::new(sample_rate)).collect();
+    let warm_up = u64::min(start, sample_rate as u64 / 2);
+    let delay = if stems.is_none() {bus.latency() as u64} else {0};
+    let mut skip = (warm_up + delay) as usize;
+    let mut position = start - warm_up;
+    let end = start + length + delay;
+    while position < end {
+        let chunk = usize::min(LoopTunesBackend::FRAME_SIZE, (end - position) as usize);
+        let time = LoopTunesBackend::time_chunk_at(position, chunk, sample_rate);
+        let buffers = match stems {
+            None => {
+                let mut samples = scene.mix_parallel(&time, sample_rate, &pool, &mut arenas);
+                bus.process(&mut samples, scene.roots.len());
+                vec![samples]
+            },
+            Some(stems) => {
+                let mut stems = scene.mix_stems(&time, sample_rate, stems, &mut arenas[0]);
+                for (samples, dc_blocker) in stems.iter_mut().zip(dc_blockers.iter_mut()) {
+                    samples.iter_mut().for_each(|sample| *sample *= compensation);
+                    dc_blocker.process(samples);
+                }
+                stems
+            },
+        };
+        let discard = usize::min(skip, chunk);
+        skip -= discard;
+        for (index, samples) in buffers.into_iter().enumerate() {
+            write(index, &samples[discard * Scene::CHANNELS..])?;
+            arenas[0].give(samples);
+        }
+        position += chunk as u64;
+    }
+    Ok(())
+}
+
+fn render(args: &[String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let mut scene = Scene::default();
+    for input in options.inputs.iter() {
+        let text = load_share_string(input)?;
+        let tree = decode_tree(&text).map_err(|err| format!("Failed to decode tree: {}", err))?;
+        tree.add_to_scene(&mut scene);
+    }
+
+    let sample_rate = options.sample_rate;
+    let (start, length) = if options.seamless_loop {
+        // Render exactly one period, such that the file loops seamlessly.
+        let period = LoopPeriod::of(scene.nodes.iter().map(|node| node.frequency))
+            .ok_or("Failed to determine the period of the tree")?;
+        if !period.exact {
+            println!("Warning: some pitches in the tree do not repeat within the period, the loop point may click.");
+        }
+        println!("Loop period is {}", period.name());
+        // Start one period in, such that the master bus has seen the end of the loop.
+        let samples = period.samples(sample_rate);
+        (samples, samples)
+    } else {
+        (0, (options.seconds * sample_rate as f64).round() as u64)
+    };
+
+    // Open a file for the mix or for each stem.
+    let paths: Vec<PathBuf> = match options.stems {
+        None => vec![options.output.clone().into()],
+        Some(stems) => {
+            let prefix = if stems == Stems::Roots {"root"} else {"leaf"};
+            (1..=scene.stem_nodes(stems).len()).map(|i| stem_path(&options.output, &format!("{}{}", prefix, i))).collect()
+        }
+    };
+    let write_error = |err| format!("Failed to write {}: {:?}", options.output, err);
+    let mut writers = paths.iter()
+        .map(|path| WavWriter::create(path, sample_rate, Scene::CHANNELS as u16))
+        .collect::<Result<Vec<_>,_>>()
+        .map_err(write_error)?;
+    write_scene(&scene, start, length, sample_rate, options.stems, |index, samples| writers[index].write(samples)).map_err(write_error)?;
+    for writer in writers {
+        writer.finish().map_err(write_error)?;
+    }
+
+    for path in paths.iter() {
+        println!("Rendered {}s of audio to {}", length as f64 / sample_rate as f64, path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::START_TUNE;
+
+    /** Renders the scene into memory, one buffer per stem. */
+    fn render_buffers(scene: &Scene, start: u64, length: u64, stems: Option<Stems>) -> Vec<Vec<f32>> {
+        let mut buffers: Vec<Vec<f32>> = Vec::new();
+        write_scene(scene, start, length, LoopTunesBackend::DEFAULT_SAMPLE_RATE, stems, |index, samples| {
+            buffers.resize_with(usize::max(buffers.len(), index + 1), Vec::new);
+            buffers[index].extend_from_slice(samples);
+            Ok(())
+        }).unwrap();
+        buffers
+    }
+
+    #[test]
+    fn stems_add_up_to_the_mix() {
+        // Starting later warms up the filters, like a --loop render.
+        let start = LoopTunesBackend::DEFAULT_SAMPLE_RATE as u64;
+        let length = 4 * LoopTunesBackend::FRAME_SIZE as u64;
+        for legacy_dc in [true, false] {
+            let mut scene = Scene::default();
+            decode_tree(START_TUNE).unwrap().add_to_scene(&mut scene);
+            // Songs saved after the legacy DC mode leave all of the offset to the filters.
+            if !legacy_dc {
+                scene.nodes.iter_mut().for_each(|node| node.average = 0.0);
+            }
+            let mix = render_buffers(&scene, start, length, None).remove(0);
+            for kind in [Stems::Roots, Stems::Leaves] {
+                let stems = render_buffers(&scene, start, length, Some(kind));
+                assert_eq!(stems.len(), scene.stem_nodes(kind).len());
+                for (index, &sample) in mix.iter().enumerate() {
+                    let sum: f32 = stems.iter().map(|stem| stem[index]).sum();
+                    assert!((sum - sample).abs() < 1e-4, "sample {}: stems add up to {}, mix is {}", index, sum, sample);
+                }
+            }
+        }
+    }
+}
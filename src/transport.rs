@@ -0,0 +1,193 @@
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+
+use crate::{is_shift, Hud, LoopPeriod, LoopTunesBackend};
+
+pub struct TransportPlugin;
+
+impl Plugin for TransportPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Transport::default())
+            .add_systems(Startup, spawn_transport)
+            // Scrubbing runs before the circles see the mouse button, such that it can block them, but after this frame's input.
+            .add_systems(PreUpdate, scrub.after(InputSystem))
+            .add_systems(Update, (transport_keys, update_length.run_if(resource_changed::<LoopTunesBackend>), update_time_readout).chain())
+            .add_systems(PostUpdate, position_scrub_bar.before(TransformSystem::TransformPropagate));
+    }
+}
+
+/** The scrub bar spans one period of the trees that are playing. */
+#[derive(Resource)]
+pub struct Transport {
+    /** Length of the scrub bar, in seconds. */
+    length: f64,
+    scrubbing: bool,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self {
+            length: Transport::DEFAULT_LENGTH,
+            scrubbing: false,
+        }
+    }
+}
+
+impl Transport {
+    /** Period of the slowest cycle, used when the period of the scene is unknown. */
+    const DEFAULT_LENGTH: f64 = 256.0;
+    /** Distance from the bottom of the screen, in pixels. */
+    const BAR_OFFSET: f32 = 45.0;
+    const BAR_HEIGHT: f32 = 3.0;
+    const HEAD_SIZE: Vec2 = Vec2::new(3.0, 15.0);
+    /** The bar can be grabbed a bit above and below the line. */
+    const GRAB_MARGIN: f32 = 8.0;
+    const DEPTH: f32 = 10.0;
+
+    /** Area of the bar in screen pixels, relative to the center of the window. */
+    fn bar(window: &Window) -> Rect {
+        let half_size = 0.5 * Vec2::new(window.width(), window.height());
+        Rect::from_center_size(Vec2::new(0.0, Self::BAR_OFFSET - half_size.y), Vec2::new(1.2 * half_size.x, Self::BAR_HEIGHT))
+    }
+
+    /** Position of the playhead on the bar, from 0 to 1. */
+    fn fraction(&self, seconds: f64) -> f32 {
+        (seconds.rem_euclid(self.length) / self.length) as f32
+    }
+}
+
+/** Stops circles from being dragged or drawn on while the scrub bar is being dragged. */
+pub fn is_scrubbing(transport: Res<Transport>) -> bool {
+    transport.scrubbing
+}
+
+#[derive(Component)] struct TimeReadout;
+#[derive(Component)] struct ScrubTrack;
+#[derive(Component)] struct ScrubHead;
+
+fn spawn_transport(mut commands: Commands) {
+    Hud::spawn(&mut commands, Vec2::new(0.0, -1.0), 0, Color::srgb(0.7, 0.7, 0.7), "")
+        .insert(TimeReadout);
+    let sprite = |color, size| SpriteBundle{
+        sprite: Sprite{color, custom_size: Some(size), ..default()},
+        ..default()
+    };
+    commands.spawn((sprite(Color::srgb(0.3, 0.3, 0.3), Vec2::ONE), ScrubTrack));
+    commands.spawn((sprite(Color::srgb(0.9, 0.9, 0.9), Transport::HEAD_SIZE), ScrubHead));
+}
+
+/** Pause with P, restart with home and seek with the arrow keys. Hold shift to take larger steps. */
+fn transport_keys(
+    mut backend: ResMut<LoopTunesBackend>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if keyboard.just_pressed(KeyCode::KeyP) {
+        let paused = backend.is_paused();
+        backend.set_paused(!paused);
+    }
+    if keyboard.just_pressed(KeyCode::Home) {
+        backend.seek(0.0);
+    }
+    let step = if is_shift(&keyboard) {10.0} else {1.0};
+    if keyboard.just_pressed(KeyCode::ArrowLeft) {
+        let seconds = backend.elapsed_seconds() - step;
+        backend.seek(seconds);
+    }
+    if keyboard.just_pressed(KeyCode::ArrowRight) {
+        let seconds = backend.elapsed_seconds() + step;
+        backend.seek(seconds);
+    }
+}
+
+/** Seeks to the time under the mouse while the scrub bar is dragged with the left mouse button. */
+fn scrub(
+    mut transport: ResMut<Transport>,
+    mut backend: ResMut<LoopTunesBackend>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window>,
+    mut last_seek: Local<Option<f64>>,
+) {
+    let Ok(window) = windows.get_single() else {return};
+    let bar = Transport::bar(window);
+    let cursor = window.cursor_position().map(|p| Vec2::new(p.x - 0.5 * window.width(), 0.5 * window.height() - p.y));
+    if mouse_buttons.just_pressed(MouseButton::Left) {
+        transport.scrubbing = cursor.is_some_and(|cursor| bar.inflate(Transport::GRAB_MARGIN).contains(cursor));
+    }
+    if transport.scrubbing && !mouse_buttons.pressed(MouseButton::Left) {
+        transport.scrubbing = false;
+    }
+    if !transport.scrubbing {
+        *last_seek = None;
+        return
+    }
+    let Some(cursor) = cursor else {return};
+    let fraction = ((cursor.x - bar.min.x) / bar.width()).clamp(0.0, 1.0);
+    let seconds = fraction as f64 * transport.length;
+    if *last_seek != Some(seconds) {
+        backend.seek(seconds);
+        *last_seek = Some(seconds);
+    }
+}
+
+/** Lets the scrub bar span one period of the scene that is being played. */
+fn update_length(
+    mut transport: ResMut<Transport>,
+    backend: Res<LoopTunesBackend>,
+) {
+    let length = LoopPeriod::of(backend.scene().nodes.iter().map(|node| node.frequency))
+        .map_or(Transport::DEFAULT_LENGTH, |period| period.seconds());
+    if transport.length != length {
+        transport.length = length;
+    }
+}
+
+/** Formats the time as minutes, seconds and tenths, e.g. 1:05.3. */
+fn format_time(seconds: f64) -> String {
+    let tenths = (seconds * 10.0).floor() as u64;
+    format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+}
+
+fn update_time_readout(
+    transport: Res<Transport>,
+    backend: Res<LoopTunesBackend>,
+    mut q_readout: Query<&mut Text, With<TimeReadout>>,
+) {
+    let Ok(mut text) = q_readout.get_single_mut() else {return};
+    let seconds = transport.fraction(backend.elapsed_seconds()) as f64 * transport.length;
+    let state = if backend.is_paused() {"paused "} else {""};
+    let label = format!("{}{} / {}", state, format_time(seconds), format_time(transport.length));
+    if text.sections[0].value != label {
+        text.sections[0].value = label;
+    }
+}
+
+/** The camera, apart from the parts of the scrub bar that follow it. */
+type TransportCamera = (With<Camera2d>, Without<ScrubTrack>, Without<ScrubHead>);
+/** The track of the scrub bar, without the head that is positioned on it. */
+type TrackOnly = (With<ScrubTrack>, Without<ScrubHead>);
+
+fn position_scrub_bar(
+    transport: Res<Transport>,
+    backend: Res<LoopTunesBackend>,
+    q_camera: Query<&Transform, TransportCamera>,
+    windows: Query<&Window>,
+    mut q_track: Query<(&mut Transform, &mut Sprite), TrackOnly>,
+    mut q_head: Query<&mut Transform, With<ScrubHead>>,
+) {
+    let Ok(camera) = q_camera.get_single() else {return};
+    let Ok(window) = windows.get_single() else {return};
+    let bar = Transport::bar(window);
+    let to_world = |position: Vec2| camera.translation + position.extend(Transport::DEPTH) * camera.scale;
+    if let Ok((mut transform, mut sprite)) = q_track.get_single_mut() {
+        transform.translation = to_world(bar.center());
+        transform.scale = camera.scale;
+        sprite.custom_size = Some(bar.size());
+    }
+    if let Ok(mut transform) = q_head.get_single_mut() {
+        let fraction = transport.fraction(backend.elapsed_seconds());
+        transform.translation = to_world(Vec2::new(bar.min.x + fraction * bar.width(), bar.center().y));
+        transform.scale = camera.scale;
+    }
+}
@@ -0,0 +1,319 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{command_line_value, println};
+
+pub struct TuningPlugin;
+
+impl Plugin for TuningPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ActiveTuning(load_from_args()));
+    }
+}
+
+/** Tuning given to new circles, None for 12-TET at A4=440. */
+#[derive(Resource, Default)]
+pub struct ActiveTuning(pub Option<Arc<Tuning>>);
+
+/** Scale from a Scala .scl file. */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    /** Cents above the tonic, without the tonic itself. The last degree is the period, usually an octave. */
+    pub degrees: Vec<f64>,
+}
+
+/** Keyboard map from a Scala .kbm file, which assigns scale degrees to MIDI keys. */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyboardMap {
+    /** Number of keys after which the mapping repeats, 0 maps every key to the next degree. */
+    pub size: u32,
+    pub first: i32,
+    pub last: i32,
+    /** Key that plays the tonic. */
+    pub middle: i32,
+    /** Key that plays the reference frequency. */
+    pub reference: i32,
+    pub frequency: f64,
+    /** Scale degree that is reached after one repetition of the mapping. */
+    pub octave_degree: u32,
+    /** Scale degree of each key in the mapping, None for keys that play nothing. */
+    pub mapping: Vec<Option<u32>>,
+}
+
+impl Default for KeyboardMap {
+    /** Middle C plays the tonic and A4 plays 440Hz. */
+    fn default() -> Self {
+        Self {
+            size: 0,
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference: 69,
+            frequency: 440.0,
+            octave_degree: 0,
+            mapping: vec![],
+        }
+    }
+}
+
+/** Lines of a Scala file, without comments. */
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+/** Parses a pitch of a .scl file, which is in cents if it contains a period and a ratio otherwise. */
+fn parse_pitch(line: &str) -> Option<f64> {
+    let value = line.split_whitespace().next()?;
+    if value.contains('.') {
+        return value.parse().ok();
+    }
+    let (p, q) = value.split_once('/').unwrap_or((value, "1"));
+    let (p, q): (u64, u64) = (p.parse().ok()?, q.parse().ok()?);
+    if p == 0 || q == 0 {return None}
+    Some(1200.0 * f64::log2(p as f64 / q as f64))
+}
+
+impl Scale {
+    pub fn parse(text: &str) -> Result<Scale, String> {
+        let mut lines = scala_lines(text);
+        let description = lines.next().ok_or("Missing description")?.trim().to_string();
+        let count: usize = lines.next().and_then(|line| line.split_whitespace().next()?.parse().ok()).ok_or("Missing number of notes")?;
+        if count == 0 {return Err("Scale has no notes".into())}
+        let degrees = lines.take(count).map(|line| parse_pitch(line).ok_or(format!("Invalid pitch: {}", line.trim()))).collect::<Result<Vec<_>,_>>()?;
+        if degrees.len() != count {return Err(format!("Expected {} notes, found {}", count, degrees.len()))}
+        if degrees[count - 1] <= 0.0 {return Err("Period must be above the tonic".into())}
+        Ok(Scale{description, degrees})
+    }
+
+    /** Cents of the degree above the tonic, which may be beyond the period or negative. */
+    fn cents(&self, degree: i64) -> f64 {
+        let count = self.degrees.len() as i64;
+        let period = self.degrees[count as usize - 1];
+        let step = degree.rem_euclid(count) as usize;
+        degree.div_euclid(count) as f64 * period + if step == 0 {0.0} else {self.degrees[step - 1]}
+    }
+}
+
+impl KeyboardMap {
+    /** One entry for every MIDI key, larger maps would never repeat. */
+    const MAX_SIZE: i32 = 128;
+
+    pub fn parse(text: &str) -> Result<KeyboardMap, String> {
+        let mut lines = scala_lines(text).map(|line| line.split_whitespace().next().unwrap_or(""));
+        let mut number = |name: &str| lines.next().and_then(|value| value.parse::<i32>().ok()).ok_or(format!("Missing {}", name));
+        let size = number("map size")?;
+        let first = number("first key")?;
+        let last = number("last key")?;
+        let middle = number("middle key")?;
+        let reference = number("reference key")?;
+        let frequency: f64 = lines.next().and_then(|value| value.parse().ok()).ok_or("Missing reference frequency")?;
+        let octave_degree = lines.next().and_then(|value| value.parse::<u32>().ok()).ok_or("Missing octave degree")?;
+        if !(0..=Self::MAX_SIZE).contains(&size) {return Err(format!("Map size must be between 0 and {}", Self::MAX_SIZE))}
+        if frequency <= 0.0 || frequency.is_nan() {return Err("Reference frequency must be positive".into())}
+        // Missing entries at the end are unmapped.
+        let mapping = (0..size).map(|_| lines.next().and_then(|value| value.parse().ok())).collect();
+        Ok(KeyboardMap{size: size as u32, first, last, middle, reference, frequency, octave_degree, mapping})
+    }
+
+    /** Scale degree played by the key. */
+    fn degree(&self, key: i32, scale: &Scale) -> Option<i64> {
+        // The keys come from a user supplied file, so their difference may not fit in an i32.
+        let offset = key as i64 - self.middle as i64;
+        if self.size == 0 {return Some(offset)}
+        let size = self.size as i64;
+        let octave_degree = if self.octave_degree == 0 {scale.degrees.len() as i64} else {self.octave_degree as i64};
+        let degree = (*self.mapping.get(offset.rem_euclid(size) as usize)?)?;
+        Some(degree as i64 + offset.div_euclid(size) * octave_degree)
+    }
+}
+
+/** The pitched part of the frequency list, generated from a scale and keyboard map. */
+#[derive(Debug)]
+pub struct Tuning {
+    pub scale: Scale,
+    pub map: KeyboardMap,
+    notes: Vec<(String, f64)>,
+}
+
+impl Tuning {
+    /** The keys from C0 up to B8, like the notes of the default frequency list. */
+    const KEYS: std::ops::RangeInclusive<i32> = 12..=119;
+    /** Notes outside the audible range are replaced, such that scales with few notes per octave do not reach into the rhythms. */
+    const AUDIBLE: std::ops::Range<f64> = 16.0..20000.0;
+    const NOTE_NAMES: [&'static str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+    pub fn new(scale: Scale, map: KeyboardMap) -> Result<Tuning, String> {
+        let reference = map.degree(map.reference, &scale).ok_or("Reference key is not mapped")?;
+        let reference = scale.cents(reference);
+        let count = scale.degrees.len() as i64;
+        let played: Vec<Option<(String, f64)>> = Self::KEYS.map(|key| {
+            if key < map.first || key > map.last {return None}
+            let degree = map.degree(key, &scale)?;
+            let frequency = map.frequency * f64::powf(2.0, (scale.cents(degree) - reference) / 1200.0);
+            if !Self::AUDIBLE.contains(&frequency) {return None}
+            // Twelve note scales keep the key names, others are named by period and degree, with the tonic of middle C's period as 4:0.
+            let name = if count == 12 {
+                format!("{}{}", Self::NOTE_NAMES[key as usize % 12], key / 12 - 1)
+            } else {
+                format!("{}:{}", degree.div_euclid(count) + 4, degree.rem_euclid(count))
+            };
+            Some((name, frequency))
+        }).collect();
+        // Every key keeps an entry, such that a circle stays on the same key when the tuning changes.
+        // Keys that play nothing take the nearest key that does, the lower one when tied.
+        let notes = (0..played.len()).map(|index| {
+            (0..played.len())
+                .flat_map(|distance| [index.checked_sub(distance), Some(index + distance)])
+                .find_map(|nearest| played.get(nearest?)?.clone())
+        }).collect::<Option<Vec<_>>>().ok_or("Tuning has no notes in the audible range")?;
+        Ok(Tuning{scale, map, notes})
+    }
+
+    /** Whether both tunings use the same scale and keyboard map. */
+    pub fn is_same(&self, other: &Tuning) -> bool {
+        self.scale == other.scale && self.map == other.map
+    }
+
+    /** Equal temperament with twelve notes per octave. */
+    fn equal_temperament() -> Scale {
+        Scale{
+            description: "12-TET".into(),
+            degrees: (1..=12).map(|step| step as f64 * 100.0).collect(),
+        }
+    }
+
+    /** Name and frequency of the note played by key C0 + index, the index is clamped to the available keys. */
+    pub fn note(&self, index: usize) -> (&str, f64) {
+        let (name, frequency) = &self.notes[index.min(self.notes.len() - 1)];
+        (name, *frequency)
+    }
+
+    pub fn note_count(&self) -> usize {
+        self.notes.len()
+    }
+
+    /**
+     * Replaces the scale or keyboard map of the tuning with the contents of a .scl or .kbm file.
+     * Returns None if the text is neither.
+     */
+    pub fn with_pasted(tuning: Option<&Tuning>, text: &str) -> Option<Result<Tuning, String>> {
+        let scale = tuning.map_or_else(Self::equal_temperament, |tuning| tuning.scale.clone());
+        let map = tuning.map_or_else(KeyboardMap::default, |tuning| tuning.map.clone());
+        if let Ok(map) = KeyboardMap::parse(text) {
+            return Some(Tuning::new(scale, map));
+        }
+        if let Ok(scale) = Scale::parse(text) {
+            return Some(Tuning::new(scale, map));
+        }
+        None
+    }
+}
+
+/** Loads the tuning given with --scale, --keyboard-map and --reference. */
+fn load_from_args() -> Option<Arc<Tuning>> {
+    let scale = command_line_value("--scale");
+    let map = command_line_value("--keyboard-map");
+    let reference = command_line_value("--reference");
+    if scale.is_none() && map.is_none() && reference.is_none() {return None}
+    let tuning = (|| {
+        let scale = scale.map_or_else(|| Ok(Tuning::equal_temperament()), |path| read_scala_file(&path, Scale::parse))?;
+        let mut map = map.map_or_else(|| Ok(KeyboardMap::default()), |path| read_scala_file(&path, KeyboardMap::parse))?;
+        if let Some(reference) = reference {
+            map.frequency = reference.parse().ok().filter(|&hz: &f64| hz > 0.0).ok_or(format!("Invalid reference frequency: {}", reference))?;
+        }
+        Tuning::new(scale, map)
+    })();
+    match tuning {
+        Ok(tuning) => Some(Arc::new(tuning)),
+        Err(err) => { println!("Failed to load tuning: {}", err); None }
+    }
+}
+
+fn read_scala_file<T>(path: &str, parse: fn(&str) -> Result<T, String>) -> Result<T, String> {
+    std::fs::read_to_string(path).map_err(|err| err.to_string()).and_then(|text| parse(&text)).map_err(|err| format!("{}: {}", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn scale_reads_cents_and_ratios() {
+        let scale = Scale::parse("! meantone.scl\n!\nQuarter-comma meantone  \n 4\n!\n193.157 cents\n5/4\n3\n2/1 octave\n").unwrap();
+        assert_eq!(scale.description, "Quarter-comma meantone");
+        assert_eq!(scale.degrees.len(), 4);
+        assert_close(scale.degrees[0], 193.157);
+        assert_close(scale.degrees[1], 1200.0 * f64::log2(1.25));
+        assert_close(scale.degrees[2], 1200.0 * f64::log2(3.0));
+        assert_close(scale.degrees[3], 1200.0);
+        assert_close(scale.cents(-1), 1200.0 * f64::log2(3.0) - 1200.0);
+        assert_close(scale.cents(5), 1393.157);
+    }
+
+    #[test]
+    fn scale_rejects_malformed_files() {
+        assert!(Scale::parse("").is_err());
+        assert!(Scale::parse("No count\n").is_err());
+        assert!(Scale::parse("Empty\n0\n").is_err());
+        assert!(Scale::parse("Too few\n3\n100.0\n2/1\n").is_err());
+        assert!(Scale::parse("Not a pitch\n1\nfifth\n").is_err());
+        assert!(Scale::parse("Zero ratio\n1\n0/1\n").is_err());
+        assert!(Scale::parse("Descending\n1\n1/2\n").is_err());
+    }
+
+    #[test]
+    fn keyboard_map_reads_unmapped_keys() {
+        let map = KeyboardMap::parse("! pentatonic.kbm\n7\n0\n127\n60\n69\n440.0\n5\n! mapping\n0\nx\n1\n2\nx\n3\n").unwrap();
+        assert_eq!((map.size, map.first, map.last, map.middle, map.reference), (7, 0, 127, 60, 69));
+        assert_close(map.frequency, 440.0);
+        assert_eq!(map.octave_degree, 5);
+        // The last entry is missing, which leaves the key unmapped.
+        assert_eq!(map.mapping, vec![Some(0), None, Some(1), Some(2), None, Some(3), None]);
+        let scale = Scale::parse("Pentatonic\n5\n200.0\n400.0\n700.0\n900.0\n2/1\n").unwrap();
+        assert_eq!(map.degree(60, &scale), Some(0));
+        assert_eq!(map.degree(61, &scale), None);
+        assert_eq!(map.degree(67, &scale), Some(5));
+        assert_eq!(map.degree(58, &scale), Some(-2));
+    }
+
+    #[test]
+    fn keyboard_map_rejects_malformed_files() {
+        assert!(KeyboardMap::parse("").is_err());
+        assert!(KeyboardMap::parse("12\n0\n127\n60\n69\n").is_err());
+        assert!(KeyboardMap::parse("-1\n0\n127\n60\n69\n440.0\n0\n").is_err());
+        assert!(KeyboardMap::parse("100000\n0\n127\n60\n69\n440.0\n0\n").is_err());
+        assert!(KeyboardMap::parse("0\n0\n127\n60\n69\n-440.0\n0\n").is_err());
+        assert!(KeyboardMap::parse("0\n0\n127\n60\n69\nNaN\n0\n").is_err());
+    }
+
+    #[test]
+    fn tuning_has_a_note_for_every_key() {
+        let tuning = Tuning::new(Tuning::equal_temperament(), KeyboardMap::default()).unwrap();
+        assert_eq!(tuning.note_count(), Tuning::KEYS.count());
+        assert_eq!(tuning.note(0).0, "C0");
+        assert_eq!(tuning.note(57), ("A4", 440.0));
+        assert_eq!(tuning.note(1000).0, "B8");
+
+        // Unmapped keys play the nearest key below or above them.
+        let map = KeyboardMap::parse("2\n0\n127\n60\n60\n261.6\n1\n0\nx\n").unwrap();
+        let tuning = Tuning::new(Tuning::equal_temperament(), map).unwrap();
+        assert_eq!(tuning.note_count(), Tuning::KEYS.count());
+        assert_eq!(tuning.note(48), tuning.note(49));
+        assert!(tuning.note(50).1 > tuning.note(49).1);
+    }
+
+    #[test]
+    fn tuning_survives_absurd_keys() {
+        let map = KeyboardMap::parse(&format!("0\n{}\n{}\n{}\n{}\n440.0\n0\n", i32::MIN, i32::MAX, i32::MIN, i32::MAX)).unwrap();
+        assert!(Tuning::new(Tuning::equal_temperament(), map).is_err());
+        let map = KeyboardMap::parse(&format!("12\n0\n127\n{}\n69\n440.0\n0\n", i32::MAX)).unwrap();
+        assert!(Tuning::new(Tuning::equal_temperament(), map).is_err());
+    }
+}
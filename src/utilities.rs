@@ -37,6 +37,20 @@ pub fn is_shift(keyboard: &ButtonInput<KeyCode>) -> bool {
     keyboard.pressed(KeyCode::ShiftLeft)  || keyboard.pressed(KeyCode::ShiftRight)
 }
 
+pub fn is_alt(keyboard: &ButtonInput<KeyCode>) -> bool {
+    keyboard.pressed(KeyCode::AltLeft)  || keyboard.pressed(KeyCode::AltRight)
+}
+
+/** Checks whether the flag was passed on the command line. */
+pub fn command_line_flag(name: &str) -> bool {
+    std::env::args().any(|arg| arg == name)
+}
+
+/** Returns the value that follows the option on the command line. */
+pub fn command_line_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
 #[cfg(target_family="wasm")]
 #[macro_export]
 macro_rules! println {
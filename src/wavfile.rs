@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/**
+ * Streams 16-bit PCM samples into a wav file.
+ * The header sizes are patched when the writer is finished.
+ * The sizes in the header are 32-bit, so a file holds somewhat less than 4 GiB of samples.
+ */
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    /** Number of bytes of samples written so far. */
+    data_size: u64,
+}
+
+impl WavWriter {
+    const HEADER_SIZE: u32 = 44;
+    const MAX_DATA_SIZE: u64 = (u32::MAX - Self::HEADER_SIZE) as u64;
+
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32, channels: u16) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(Self::HEADER_SIZE - 8).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(Self{file, channels, data_size: 0})
+    }
+
+    /** Whether the given number of samples still fits in the file. */
+    pub fn has_room(&self, samples: usize) -> bool {
+        self.data_size + samples as u64 * 2 <= Self::MAX_DATA_SIZE
+    }
+
+    /** Writes interleaved samples, clipping them to the -1..1 range. Fails if the file would become too large. */
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        if !self.has_room(samples.len()) {
+            return Err(Error::other("wav file cannot be larger than 4 GiB"));
+        }
+        for &sample in samples.iter() {
+            let value = (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u64 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        // Only whole frames count.
+        let block_align = self.channels as u64 * 2;
+        let data_size = (self.data_size / block_align * block_align) as u32;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(Self::HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(Self::HEADER_SIZE as u64 - 4))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}